@@ -15,12 +15,12 @@ final getIt = GetIt.instance;
 
 void setupLocator() {
   getIt.registerLazySingleton<AuthApiClient>(() => AuthApiClient());
-  getIt.registerLazySingleton<WebSocketService>(() => WebSocketService());
+  getIt.registerLazySingleton<WebSocketService>(() => WebSocketService(authRepository: getIt<AuthRepository>()));
 
   getIt.registerLazySingleton<AuthRepository>(() => AuthRepository(apiClient: 
 getIt<AuthApiClient>()));
   getIt.registerLazySingleton<ConversationRepository>(() => 
-ConversationRepository());
+ConversationRepository(authRepository: getIt<AuthRepository>()));
   
   getIt.registerFactory<AuthBloc>(() => AuthBloc(authRepository: 
 getIt<AuthRepository>()));
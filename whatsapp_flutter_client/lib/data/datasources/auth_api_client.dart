@@ -9,4 +9,8 @@ class AuthApiClient {
 phoneNumber, 'otp': otp});
     return response.data;
   }
+  Future<Map<String, dynamic>> refresh(String refreshToken) async {
+    final response = await _dio.post('/auth/refresh', data: {'refresh_token': refreshToken});
+    return response.data;
+  }
 }
@@ -1,17 +1,20 @@
-import 'package:flutter_secure_storage/flutter_secure_storage.dart';
 import 'package:web_socket_channel/web_socket_channel.dart';
+import 'package:whatsapp_flutter_client/data/repositories/auth_repository.dart';
 import 'dart:async';
 import 'dart:convert';
 class WebSocketService {
-  final _secureStorage = const FlutterSecureStorage();
+  final AuthRepository _authRepository;
   WebSocketChannel? _channel;
   StreamController<dynamic>? _streamController;
   bool _isConnected = false;
 
+  WebSocketService({required AuthRepository authRepository}) : _authRepository = authRepository;
+
   Stream<dynamic>? get messages => _streamController?.stream;
   
   Future<void> connect() async {
-    final token = await _secureStorage.read(key: 'jwt_token');
+    // The token is only checked on connect, so reconnects must not reuse an expired one
+    final token = await _authRepository.getValidToken();
     if (token == null) throw Exception('Auth token not found.');
     if (_isConnected) return;
     disconnect();
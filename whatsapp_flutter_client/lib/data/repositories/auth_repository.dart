@@ -1,14 +1,54 @@
+import 'package:dio/dio.dart';
 import 'package:flutter_secure_storage/flutter_secure_storage.dart';
 import 'package:whatsapp_flutter_client/data/datasources/auth_api_client.dart';
 class AuthRepository {
   final AuthApiClient _apiClient;
   final _storage = const FlutterSecureStorage();
+  Future<String?>? _refreshing;
   AuthRepository({required AuthApiClient apiClient}) : _apiClient = apiClient;
   Future<void> sendOtp(String phoneNumber) => _apiClient.sendOtp(phoneNumber);
   Future<void> verifyOtp(String phoneNumber, String otp) async {
     final data = await _apiClient.verifyOtp(phoneNumber, otp);
-    await _storage.write(key: 'jwt_token', value: data['token']);
+    await _saveTokens(data);
     await _storage.write(key: 'user_id', value: data['user_id']);
   }
   Future<String?> getToken() => _storage.read(key: 'jwt_token');
+
+  // Access tokens only live for minutes, so one that is about to expire is refreshed first.
+  // Returns null once the user has to sign in again.
+  Future<String?> getValidToken() async {
+    final expiresAt = DateTime.tryParse(await _storage.read(key: 'jwt_expires_at') ?? '');
+    if (expiresAt != null && DateTime.now().isBefore(expiresAt.subtract(const Duration(seconds: 30)))) {
+      return getToken();
+    }
+    return refreshToken();
+  }
+
+  // Refresh tokens rotate on every use and the server signs the device out when one is used
+  // twice, so concurrent callers share a single refresh.
+  Future<String?> refreshToken() => _refreshing ??= _refresh().whenComplete(() => _refreshing = null);
+
+  Future<String?> _refresh() async {
+    final refreshToken = await _storage.read(key: 'refresh_token');
+    if (refreshToken == null) return null;
+    try {
+      final data = await _apiClient.refresh(refreshToken);
+      await _saveTokens(data);
+      return data['token'] as String;
+    } on DioException catch (e) {
+      // Only a rejected refresh token signs the user out; after a network error the next call tries again
+      if (e.response?.statusCode == 401) {
+        await _storage.deleteAll();
+        return null;
+      }
+      return getToken();
+    }
+  }
+
+  Future<void> _saveTokens(Map<String, dynamic> data) async {
+    await _storage.write(key: 'jwt_token', value: data['token']);
+    await _storage.write(key: 'refresh_token', value: data['refresh_token']);
+    final expiresAt = DateTime.now().add(Duration(seconds: data['expires_in'] as int));
+    await _storage.write(key: 'jwt_expires_at', value: expiresAt.toIso8601String());
+  }
 }
@@ -1,19 +1,31 @@
 import 'package:dio/dio.dart';
-import 'package:flutter_secure_storage/flutter_secure_storage.dart';
 import 'package:whatsapp_flutter_client/data/models/conversation.dart';
 import 'package:whatsapp_flutter_client/data/models/chat_message.dart';
+import 'package:whatsapp_flutter_client/data/repositories/auth_repository.dart';
 
 class ConversationRepository {
   final _dio = Dio(BaseOptions(baseUrl: 'http://127.0.0.1:3000/api'));
-  final _storage = const FlutterSecureStorage();
+  final AuthRepository _authRepository;
 
-  ConversationRepository() {
-    _dio.interceptors.add(InterceptorsWrapper(onRequest: (options, handler) async 
-{
-      options.headers['Authorization'] = 'Bearer ${await _storage.read(key: 
-'jwt_token')}';
-      handler.next(options);
-    }));
+  ConversationRepository({required AuthRepository authRepository}) : _authRepository = authRepository {
+    _dio.interceptors.add(InterceptorsWrapper(
+      onRequest: (options, handler) async {
+        options.headers['Authorization'] = 'Bearer ${await _authRepository.getValidToken()}';
+        handler.next(options);
+      },
+      onError: (error, handler) async {
+        // The access token can still expire or be revoked in flight: refresh once and retry
+        if (error.response?.statusCode != 401 || error.requestOptions.extra['retried'] == true) {
+          return handler.next(error);
+        }
+        if (await _authRepository.refreshToken() == null) return handler.next(error);
+        try {
+          handler.resolve(await _dio.fetch(error.requestOptions..extra['retried'] = true));
+        } on DioException catch (e) {
+          handler.next(e);
+        }
+      },
+    ));
   }
 
   Future<List<Conversation>> getConversations() async {
@@ -26,7 +38,7 @@ Conversation.fromJson(json)).toList();
   Future<List<ChatMessage>> getMessageHistory(String conversationId) async {
     try {
       final response = await _dio.get('/conversations/$conversationId/messages');
-      final data = response.data as List;
+      final data = response.data['messages'] as List;
       return data.map((json) => ChatMessage.fromJson(json)).toList();
     } catch (e) {
       throw Exception('Failed to fetch message history');
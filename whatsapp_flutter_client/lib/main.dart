@@ -41,7 +41,7 @@ class AuthGate extends StatelessWidget {
   @override
   Widget build(BuildContext context) {
     return FutureBuilder<String?>(
-      future: GetIt.I.get<AuthRepository>().getToken(),
+      future: GetIt.I.get<AuthRepository>().getValidToken(),
       builder: (context, snapshot) {
         if (snapshot.connectionState == ConnectionState.waiting) {
           return const Scaffold(body: Center(child: CircularProgressIndicator()));
@@ -0,0 +1,2 @@
+-- System messages record group changes ("X added Y") in the conversation history.
+ALTER TYPE message_type ADD VALUE 'system';
@@ -0,0 +1,3 @@
+-- Per-recipient delivery state. messages.status holds the aggregate over all recipients.
+CREATE TABLE message_receipts (message_id UUID NOT NULL REFERENCES messages(id) ON DELETE CASCADE, user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE, status message_status NOT NULL, updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(), PRIMARY KEY (message_id, user_id));
+CREATE INDEX idx_message_receipts_user_id ON message_receipts(user_id);
@@ -0,0 +1,4 @@
+-- Lets offline sync replay the history a user missed in groups they were removed from or left.
+CREATE TABLE conversation_departures (conversation_id UUID NOT NULL REFERENCES conversations(id) ON DELETE CASCADE, user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE, departed_at TIMESTAMPTZ NOT NULL DEFAULT NOW());
+CREATE INDEX idx_conversation_departures_user_id ON conversation_departures(user_id, departed_at);
+CREATE INDEX idx_message_receipts_updated_at ON message_receipts(updated_at);
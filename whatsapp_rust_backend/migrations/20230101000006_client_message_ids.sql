@@ -0,0 +1,3 @@
+-- Clients tag each send with their own id so retries can be recognised and acknowledged once.
+ALTER TABLE messages ADD COLUMN client_message_id UUID;
+CREATE UNIQUE INDEX idx_messages_sender_client_message_id ON messages(sender_id, client_message_id);
@@ -0,0 +1,3 @@
+-- Image, video and audio messages reference an uploaded object; the caption stays in content.
+ALTER TABLE messages ADD COLUMN media JSONB;
+CREATE INDEX idx_messages_media_key ON messages ((media->>'key')) WHERE media IS NOT NULL;
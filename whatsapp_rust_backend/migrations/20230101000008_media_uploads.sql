@@ -0,0 +1,7 @@
+-- Every presigned upload is recorded with its owner so messages can only reference their own files.
+ALTER TYPE message_type ADD VALUE 'document';
+CREATE TYPE media_kind AS ENUM ('image', 'video', 'audio', 'document');
+CREATE TYPE media_upload_state AS ENUM ('pending', 'uploaded', 'attached', 'deleted');
+CREATE TABLE media_uploads (id UUID PRIMARY KEY DEFAULT uuid_generate_v4(), owner_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE, object_key TEXT NOT NULL UNIQUE, kind media_kind NOT NULL, mime_type TEXT NOT NULL, size_bytes BIGINT NOT NULL, state media_upload_state NOT NULL DEFAULT 'pending', created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(), updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW());
+CREATE INDEX idx_media_uploads_owner_id ON media_uploads(owner_id);
+CREATE INDEX idx_media_uploads_state_created_at ON media_uploads(state, created_at);
@@ -0,0 +1,2 @@
+-- Thumbnails and a blurhash placeholder generated in the background once an image upload completes.
+ALTER TABLE media_uploads ADD COLUMN width INT, ADD COLUMN height INT, ADD COLUMN thumbnails JSONB NOT NULL DEFAULT '[]', ADD COLUMN blurhash TEXT;
@@ -0,0 +1,4 @@
+-- Resumable uploads: the store's multipart id on the upload, and one row per part the client has finished.
+ALTER TABLE media_uploads ADD COLUMN multipart_id TEXT, ADD COLUMN part_size BIGINT;
+CREATE TABLE media_upload_parts (upload_id UUID NOT NULL REFERENCES media_uploads(id) ON DELETE CASCADE, part_number INT NOT NULL, etag TEXT NOT NULL, created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(), PRIMARY KEY (upload_id, part_number));
+CREATE INDEX idx_media_uploads_multipart_pending ON media_uploads(updated_at) WHERE multipart_id IS NOT NULL AND state = 'pending';
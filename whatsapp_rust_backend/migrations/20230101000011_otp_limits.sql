@@ -0,0 +1,5 @@
+-- Wrong guesses per code, and a log of sends for the per-phone and per-IP resend limits.
+ALTER TABLE temp_otps ADD COLUMN attempts INT NOT NULL DEFAULT 0;
+CREATE TABLE otp_sends (id BIGSERIAL PRIMARY KEY, phone_number TEXT NOT NULL, ip_address TEXT NOT NULL, sent_at TIMESTAMPTZ NOT NULL DEFAULT NOW());
+CREATE INDEX idx_otp_sends_phone_number_sent_at ON otp_sends(phone_number, sent_at);
+CREATE INDEX idx_otp_sends_ip_address_sent_at ON otp_sends(ip_address, sent_at);
@@ -0,0 +1,6 @@
+-- Phone numbers are stored in E.164. Existing rows are converted at startup by utils::phone::backfill_e164, with the same parsing and DEFAULT_PHONE_REGION as logins; numbers that are not valid, or whose E.164 form another account already has, are left as they are and listed in phone_number_review.
+CREATE TYPE phone_review_reason AS ENUM ('invalid', 'collision');
+CREATE TABLE phone_number_review (user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE, original_phone_number TEXT NOT NULL, reason phone_review_reason NOT NULL, normalized_phone_number TEXT, kept_user_id UUID REFERENCES users(id) ON DELETE CASCADE, detected_at TIMESTAMPTZ NOT NULL DEFAULT NOW());
+-- Outstanding codes expire within minutes and send history only feeds rate limits, so rows in the old form are dropped
+DELETE FROM temp_otps WHERE phone_number !~ '^\+[0-9]+$';
+DELETE FROM otp_sends WHERE phone_number !~ '^\+[0-9]+$';
@@ -0,0 +1,4 @@
+-- Refresh tokens are stored as SHA-256 hashes. Every login starts a family identified by its device_id; rotating a token marks it rotated and adds its successor to the family.
+CREATE TABLE refresh_tokens (id UUID PRIMARY KEY DEFAULT uuid_generate_v4(), user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE, device_id UUID NOT NULL, token_hash TEXT NOT NULL UNIQUE, created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(), expires_at TIMESTAMPTZ NOT NULL, rotated_at TIMESTAMPTZ, revoked_at TIMESTAMPTZ);
+CREATE INDEX idx_refresh_tokens_device_id ON refresh_tokens(device_id);
+CREATE INDEX idx_refresh_tokens_expires_at ON refresh_tokens(expires_at);
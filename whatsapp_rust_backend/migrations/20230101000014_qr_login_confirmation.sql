@@ -0,0 +1,3 @@
+-- QR logins are confirmed on the phone before the browser is signed in, and the browser's tokens are minted when it collects them (once, marking the session consumed) instead of being stored.
+ALTER TYPE qr_session_status ADD VALUE 'consumed';
+ALTER TABLE qr_sessions DROP COLUMN jwt;
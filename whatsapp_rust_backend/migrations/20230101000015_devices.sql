@@ -0,0 +1,6 @@
+-- Every signed-in device, keyed by the device_id its tokens carry. Devices that already hold live refresh tokens are backfilled; QR sessions remember what the browser said about itself until it is signed in.
+ALTER TYPE device_platform ADD VALUE 'desktop';
+CREATE TABLE devices (id UUID PRIMARY KEY, user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE, name TEXT NOT NULL, platform device_platform, linked_at TIMESTAMPTZ NOT NULL DEFAULT NOW(), last_active_at TIMESTAMPTZ NOT NULL DEFAULT NOW());
+CREATE INDEX idx_devices_user_id ON devices(user_id);
+INSERT INTO devices (id, user_id, name, linked_at, last_active_at) SELECT device_id, user_id, 'Unknown device', MIN(created_at), MAX(created_at) FROM refresh_tokens WHERE revoked_at IS NULL GROUP BY device_id, user_id;
+ALTER TABLE qr_sessions ADD COLUMN device_name TEXT, ADD COLUMN device_platform device_platform;
@@ -0,0 +1,2 @@
+-- Signed prekeys are stored with their id and the identity key's signature over them. Bundles uploaded before this have neither; they are kept but not handed out until their owner uploads a signed bundle.
+ALTER TABLE user_key_bundles ADD COLUMN signed_pre_key_id INT, ADD COLUMN signed_pre_key_signature TEXT;
@@ -0,0 +1,4 @@
+-- One-time prekeys handed out per requester, so one user cannot drain another's prekeys
+CREATE TABLE pre_key_claims (requester_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE, user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE, claimed_at TIMESTAMPTZ NOT NULL DEFAULT NOW());
+CREATE INDEX idx_pre_key_claims_requester_id ON pre_key_claims(requester_id, claimed_at);
+CREATE INDEX idx_pre_key_claims_claimed_at ON pre_key_claims(claimed_at);
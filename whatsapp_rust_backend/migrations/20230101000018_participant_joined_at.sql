@@ -0,0 +1,2 @@
+-- When each member joined, so the admin hand-over on leave picks the longest-standing member
+ALTER TABLE conversation_participants ADD COLUMN joined_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
@@ -1 +1 @@
-pub mod server; pub mod session;
+pub mod qr_login; pub mod server; pub mod session; pub mod thumbnailer;
@@ -0,0 +1,135 @@
+use crate::actors::server::ChatServer;
+use crate::handlers::qr_auth_handler::release_qr_session;
+use actix::{Actor, ActorContext, ActorFutureExt, Addr, AsyncContext, Context, ContextFutureSpawner, Handler, Message as ActixMessage, Recipient, Running, StreamHandler, WrapFuture};
+use actix_web_actors::ws;
+use serde_json::json;
+use sqlx::PgPool;
+use std::{collections::HashMap, time::{Duration, Instant}};
+use uuid::Uuid;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A browser started watching `session_id`; `watcher_id` is its connection.
+#[derive(ActixMessage, Debug)] #[rtype(result = "()")] pub struct WatchQrSession { pub session_id: Uuid, pub watcher_id: Uuid, pub addr: Recipient<QrEvent> }
+#[derive(ActixMessage, Debug)] #[rtype(result = "()")] pub struct UnwatchQrSession { pub session_id: Uuid, pub watcher_id: Uuid }
+/// Sent by the REST layer after a phone scanned the session.
+#[derive(ActixMessage, Debug)] #[rtype(result = "()")] pub struct QrScanned { pub session_id: Uuid }
+/// Sent by the REST layer after the phone confirmed the session, and by a watcher that connects
+/// to an already confirmed one.
+#[derive(ActixMessage, Debug)] #[rtype(result = "()")] pub struct QrConfirmed { pub session_id: Uuid }
+/// An event for the watching browser; the socket is closed after it when `last` is set.
+#[derive(ActixMessage, Debug)] #[rtype(result = "()")] pub struct QrEvent { pub payload: String, pub last: bool }
+
+/// Pushes QR login progress to the browser showing the code, so it does not have to poll.
+/// Each session has at most one watcher; a newer connection replaces the older one.
+pub struct QrLoginServer { watchers: HashMap<Uuid, (Uuid, Recipient<QrEvent>)>, db_pool: PgPool, chat_server: Addr<ChatServer> }
+
+impl QrLoginServer {
+    pub fn new(db_pool: PgPool, chat_server: Addr<ChatServer>) -> Self { Self { watchers: HashMap::new(), db_pool, chat_server } }
+}
+
+impl Actor for QrLoginServer { type Context = Context<Self>; }
+
+impl Handler<WatchQrSession> for QrLoginServer {
+    type Result = ();
+    fn handle(&mut self, msg: WatchQrSession, _: &mut Context<Self>) {
+        if let Some((_, previous)) = self.watchers.insert(msg.session_id, (msg.watcher_id, msg.addr)) {
+            previous.do_send(QrEvent { payload: json!({"event": "replaced"}).to_string(), last: true });
+        }
+    }
+}
+impl Handler<UnwatchQrSession> for QrLoginServer {
+    type Result = ();
+    fn handle(&mut self, msg: UnwatchQrSession, _: &mut Context<Self>) {
+        // A replaced watcher must not unregister its successor
+        if self.watchers.get(&msg.session_id).is_some_and(|(id, _)| *id == msg.watcher_id) {
+            self.watchers.remove(&msg.session_id);
+        }
+    }
+}
+impl Handler<QrScanned> for QrLoginServer {
+    type Result = ();
+    fn handle(&mut self, msg: QrScanned, _: &mut Context<Self>) {
+        if let Some((_, watcher)) = self.watchers.get(&msg.session_id) {
+            watcher.do_send(QrEvent { payload: json!({"event": "scanned"}).to_string(), last: false });
+        }
+    }
+}
+impl Handler<QrConfirmed> for QrLoginServer {
+    type Result = ();
+    fn handle(&mut self, msg: QrConfirmed, ctx: &mut Context<Self>) {
+        // Without a watcher the browser collects its tokens by polling
+        if !self.watchers.contains_key(&msg.session_id) { return; }
+        let (db_pool, chat_server, session_id) = (self.db_pool.clone(), self.chat_server.clone(), msg.session_id);
+        async move { release_qr_session(&db_pool, &chat_server, session_id).await }
+            .into_actor(self)
+            .map(move |released, act, _| match released {
+                Ok(Some(auth)) => {
+                    let Some((_, watcher)) = act.watchers.remove(&session_id) else { return };
+                    watcher.do_send(QrEvent { payload: json!({"event": "authenticated", "data": auth}).to_string(), last: true });
+                }
+                // A poll collected the tokens first
+                Ok(None) => {}
+                Err(e) => log::error!("Failed to release QR session {}: {:?}", session_id, e),
+            })
+            .spawn(ctx);
+    }
+}
+
+/// The socket of a browser waiting on `session_id`. It reports the state it found on connect,
+/// then whatever `QrLoginServer` pushes, and says `expired` once the code can no longer be used.
+pub struct QrLoginSession { id: Uuid, session_id: Uuid, status: &'static str, expires_in: Duration, hb: Instant, server_addr: Addr<QrLoginServer> }
+
+impl QrLoginSession {
+    pub fn new(session_id: Uuid, status: &'static str, expires_in: Duration, server_addr: Addr<QrLoginServer>) -> Self {
+        Self { id: Uuid::new_v4(), session_id, status, expires_in, hb: Instant::now(), server_addr }
+    }
+}
+
+impl Actor for QrLoginSession {
+    type Context = ws::WebsocketContext<Self>;
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT { ctx.stop(); } else { ctx.ping(b""); }
+        });
+        ctx.run_later(self.expires_in, |_, ctx| {
+            ctx.text(json!({"event": "expired"}).to_string());
+            ctx.close(Some(ws::CloseCode::Normal.into()));
+            ctx.stop();
+        });
+        self.server_addr.do_send(WatchQrSession { session_id: self.session_id, watcher_id: self.id, addr: ctx.address().recipient() });
+        if self.status == "authenticated" {
+            // Confirmed before this socket opened: the tokens are delivered here instead of to a poll
+            self.server_addr.do_send(QrConfirmed { session_id: self.session_id });
+        } else {
+            ctx.text(json!({"event": self.status}).to_string());
+        }
+    }
+    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        self.server_addr.do_send(UnwatchQrSession { session_id: self.session_id, watcher_id: self.id });
+        Running::Stop
+    }
+}
+impl Handler<QrEvent> for QrLoginSession {
+    type Result = ();
+    fn handle(&mut self, msg: QrEvent, ctx: &mut Self::Context) {
+        ctx.text(msg.payload);
+        if msg.last {
+            ctx.close(Some(ws::CloseCode::Normal.into()));
+            ctx.stop();
+        }
+    }
+}
+/// The browser only listens; anything but heartbeats and close is ignored.
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for QrLoginSession {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Ping(msg)) => { self.hb = Instant::now(); ctx.pong(&msg); }
+            Ok(ws::Message::Pong(_)) => self.hb = Instant::now(),
+            Ok(ws::Message::Close(reason)) => { ctx.close(reason); ctx.stop(); }
+            Ok(_) => {}
+            Err(_) => ctx.stop(),
+        }
+    }
+}
@@ -1,116 +1,596 @@
-use actix::{Actor, ActorFutureExt, AsyncContext, Context, ContextFutureSpawner, 
-Handler, Message as ActixMessage, Recipient, WrapFuture};
+use actix::{Actor, ActorFutureExt, Context, ContextFutureSpawner, Handler, Message as ActixMessage, Recipient, WrapFuture};
 use serde::Serialize;
 use serde_json::json;
-use sqlx::PgPool;
+use sqlx::{types::Json, PgPool};
 use std::collections::{HashMap, HashSet};
 use uuid::Uuid;
 use actix::fut;
+use chrono::{DateTime, Duration, Utc};
+use crate::models::{ChatMessage, ConversationWithParticipants, Device, MediaAttachment, MediaKind, MediaUploadState, MessageStatus, MessageType, Thumbnail};
+use crate::handlers::{conversation_handler::fetch_conversations_of, media_handler::user_upload_prefix};
+use crate::utils::authz::{require_participant, AuthzError};
 
-#[derive(ActixMessage, Debug, Serialize)] #[rtype(result = "()")] pub struct 
-ClientMessage { pub sender_id: Uuid, pub conversation_id: Uuid, pub content: 
-String }
-#[derive(ActixMessage, Debug)] #[rtype(result = "()")] pub struct Connect { pub 
-user_id: Uuid, pub addr: Recipient<WsMessage> }
-#[derive(ActixMessage, Debug)] #[rtype(result = "()")] pub struct Disconnect { pub 
-user_id: Uuid }
-#[derive(ActixMessage, Debug, Serialize)] #[rtype(result = "()")] pub struct 
-Typing { pub sender_id: Uuid, pub conversation_id: Uuid, pub is_typing: bool }
-#[derive(ActixMessage, Debug, Serialize)] #[rtype(result = "()")] pub struct 
-WsMessage(pub String);
-
-pub struct ChatServer { sessions: HashMap<Uuid, Recipient<WsMessage>>, 
-conversations: HashMap<Uuid, HashSet<Uuid>>, db_pool: PgPool }
+/// A message sent by a client. For media messages `content` is the (possibly empty) caption.
+#[derive(ActixMessage, Debug)] #[rtype(result = "()")] pub struct ClientMessage {
+    pub session_id: Uuid, pub sender_id: Uuid, pub conversation_id: Uuid, pub client_message_id: Option<Uuid>,
+    pub message_type: MessageType, pub content: String, pub media: Option<MediaAttachment>,
+}
+#[derive(ActixMessage, Debug)] #[rtype(result = "()")] pub struct Connect { pub session_id: Uuid, pub user_id: Uuid, pub device_id: Uuid, pub addr: Recipient<WsMessage>, pub deliver: Recipient<Deliver>, pub close: Recipient<CloseSession> }
+#[derive(ActixMessage, Debug)] #[rtype(result = "()")] pub struct Disconnect { pub session_id: Uuid }
+/// Closes every connection opened with an access token of `device_id`, e.g. after it logged out.
+#[derive(ActixMessage, Debug)] #[rtype(result = "()")] pub struct DisconnectDevice { pub device_id: Uuid, pub reason: &'static str }
+/// A device signed in as `user_id`; the user's connected devices get a `device_linked` event.
+#[derive(ActixMessage, Debug)] #[rtype(result = "()")] pub struct DeviceLinked { pub user_id: Uuid, pub device: Device }
+/// Tells a session to close its socket with `reason`.
+#[derive(ActixMessage, Debug)] #[rtype(result = "()")] pub struct CloseSession { pub reason: &'static str }
+#[derive(ActixMessage, Debug, Serialize)] #[rtype(result = "()")] pub struct Typing { #[serde(skip)] pub session_id: Uuid, pub sender_id: Uuid, pub conversation_id: Uuid, pub is_typing: bool }
+#[derive(ActixMessage, Debug, Serialize)] #[rtype(result = "()")] pub struct WsMessage(pub String);
+/// A `new_message` event for a recipient; the session acknowledges it with `Delivered` once written.
+#[derive(ActixMessage, Debug)] #[rtype(result = "()")] pub struct Deliver { pub message_id: Uuid, pub payload: String }
+#[derive(ActixMessage, Debug)] #[rtype(result = "()")] pub struct Delivered { pub user_id: Uuid, pub message_id: Uuid }
+#[derive(ActixMessage, Debug)] #[rtype(result = "()")] pub struct MarkRead { pub session_id: Uuid, pub user_id: Uuid, pub conversation_id: Uuid, pub up_to_message_id: Uuid }
+/// Replays everything the user missed after `since`, then reports the cursor to resume from next time
+/// along with the user's current conversations. Events from the `SYNC_OVERLAP` before `since` are
+/// replayed again, so clients drop messages whose `id` they already have.
+#[derive(ActixMessage, Debug)] #[rtype(result = "()")] pub struct SyncRequest { pub session_id: Uuid, pub user_id: Uuid, pub since: Option<DateTime<Utc>> }
+/// Sent by the REST layer after a group changes. `members` is the membership after the change;
+/// `removed` users are still sent `events` before they are dropped from the cache.
+#[derive(ActixMessage, Debug)] #[rtype(result = "()")] pub struct GroupChanged { pub conversation_id: Uuid, pub members: Vec<Uuid>, pub removed: Vec<Uuid>, pub events: Vec<String> }
+/// Previews for an image that was already sent became available.
+#[derive(ActixMessage, Debug)] #[rtype(result = "()")] pub struct MediaUpdated { pub conversation_id: Uuid, pub message_id: Uuid, pub media: MediaAttachment }
+/// Sent by the REST layer once a conversation exists, so its members are cached and notified on
+/// every session except the creating device, which already has it from the HTTP response.
+#[derive(ActixMessage, Debug)] #[rtype(result = "()")] pub struct ConversationCreated { pub device_id: Uuid, pub conversation: ConversationWithParticipants }
+
+struct Session { user_id: Uuid, device_id: Uuid, addr: Recipient<WsMessage>, deliver: Recipient<Deliver>, close: Recipient<CloseSession> }
+
+/// Routes events between connected sessions. `sessions` is keyed by connection id and
+/// `user_sessions` indexes them per user, so every device of a user receives its events.
+/// `conversations` mirrors `conversation_participants` for every conversation that has at
+/// least one connected member, so fan-out never leaves it.
+pub struct ChatServer { sessions: HashMap<Uuid, Session>, user_sessions: HashMap<Uuid, HashSet<Uuid>>, conversations: HashMap<Uuid, HashSet<Uuid>>, db_pool: PgPool }
 impl ChatServer {
-    pub fn new(db_pool: PgPool) -> Self { Self { sessions: HashMap::new(), 
-conversations: HashMap::new(), db_pool } }
-    fn broadcast(&self, _conv_id: &Uuid, msg: &str, skip_id: Option<Uuid>) {
-        for (user_id, session) in &self.sessions {
-            if skip_id.map_or(true, |id| *user_id != id) {
-                session.do_send(WsMessage(msg.to_owned()));
+    pub fn new(db_pool: PgPool) -> Self { Self { sessions: HashMap::new(), user_sessions: HashMap::new(), conversations: HashMap::new(), db_pool } }
+    fn broadcast(&self, conv_id: &Uuid, msg: &str, skip_id: Option<Uuid>) {
+        let Some(members) = self.conversations.get(conv_id) else { return };
+        for user_id in members.iter().filter(|id| skip_id != Some(**id)) {
+            self.send_to_user(user_id, msg);
+        }
+    }
+    /// Everyone who shares at least one conversation with `user_id`, excluding the user.
+    fn peers_of(&self, user_id: &Uuid) -> HashSet<Uuid> {
+        self.conversations.values().filter(|members| members.contains(user_id)).flatten().filter(|id| *id != user_id).copied().collect()
+    }
+    fn sessions_of<'a>(&'a self, user_id: &Uuid) -> impl Iterator<Item = &'a Session> + 'a {
+        self.user_sessions.get(user_id).into_iter().flatten().filter_map(|id| self.sessions.get(id))
+    }
+    fn send_to_user(&self, user_id: &Uuid, msg: &str) {
+        for session in self.sessions_of(user_id) {
+            session.addr.do_send(WsMessage(msg.to_owned()));
+        }
+    }
+    fn send_to_session(&self, session_id: &Uuid, msg: &str) {
+        if let Some(session) = self.sessions.get(session_id) {
+            session.addr.do_send(WsMessage(msg.to_owned()));
+        }
+    }
+    /// Sends a persisted message to every member; recipients other than the sender confirm delivery.
+    fn deliver_message(&self, message: &ChatMessage) {
+        let Some(members) = self.conversations.get(&message.conversation_id) else { return };
+        let payload = json!({"event": "new_message", "data": message}).to_string();
+        for user_id in members {
+            for session in self.sessions_of(user_id) {
+                if *user_id == message.sender_id {
+                    session.addr.do_send(WsMessage(payload.clone()));
+                } else {
+                    session.deliver.do_send(Deliver { message_id: message.id, payload: payload.clone() });
+                }
             }
         }
     }
+    fn send_to_peers(&self, user_id: &Uuid, msg: &str) {
+        for peer in self.peers_of(user_id) {
+            self.send_to_user(&peer, msg);
+        }
+    }
+    /// Drops cached memberships for conversations that no longer have a connected member.
+    fn prune_conversations(&mut self) {
+        let online = &self.user_sessions;
+        self.conversations.retain(|_, members| members.iter().any(|id| online.contains_key(id)));
+    }
 }
 impl Actor for ChatServer { type Context = Context<Self>; }
 
+impl ClientMessage {
+    /// Checks the payload matches its `message_type`; attachments must be the sender's own uploads.
+    fn validate(&self) -> Result<(), &'static str> {
+        let media = match (self.message_type, &self.media) {
+            (MessageType::System, _) => return Err("System messages cannot be sent by clients"),
+            (MessageType::Text, Some(_)) => return Err("Text messages cannot carry media"),
+            (MessageType::Text, None) if self.content.trim().is_empty() => return Err("Text messages cannot be empty"),
+            (MessageType::Text, None) => return Ok(()),
+            (_, None) => return Err("Media messages need an attachment"),
+            (_, Some(media)) => media,
+        };
+        if !media.key.starts_with(&user_upload_prefix(self.sender_id)) || media.key.contains("..") {
+            return Err("The attachment was not uploaded by the sender");
+        }
+        if media.size_bytes <= 0 || media.width.is_some_and(|w| w <= 0) || media.height.is_some_and(|h| h <= 0) || media.duration_ms.is_some_and(|d| d <= 0) {
+            return Err("The attachment's size, dimensions or duration are invalid");
+        }
+        Ok(())
+    }
+}
+
+/// What became of a `ClientMessage`; every outcome is reported back to the sender.
+enum SendOutcome { Saved(ChatMessage, Option<Vec<Uuid>>), Duplicate(ChatMessage), Invalid(&'static str), Rejected(AuthzError), Failed }
+
+/// Checks a media attachment against the sender's recorded upload. The recorded mime type and
+/// size replace whatever the client claimed, since those are what the presigned PUT enforced,
+/// and any previews generated so far are attached.
+async fn attach_upload(pool: &PgPool, sender_id: Uuid, message_type: MessageType, media: &mut MediaAttachment) -> Result<Result<(), &'static str>, sqlx::Error> {
+    let upload = sqlx::query!(
+        r#"SELECT kind as "kind: MediaKind", mime_type, size_bytes, width, height, thumbnails as "thumbnails: Json<Vec<Thumbnail>>", blurhash, state as "state: MediaUploadState" FROM media_uploads WHERE object_key = $1 AND owner_id = $2 AND state <> 'deleted'"#,
+        media.key,
+        sender_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    let Some(upload) = upload else { return Ok(Err("The attachment was not uploaded by the sender")) };
+    if upload.state == MediaUploadState::Pending {
+        return Ok(Err("The attachment's upload has not been completed"));
+    }
+    if upload.kind.message_type() != message_type {
+        return Ok(Err("The attachment's type does not match the message type"));
+    }
+    media.mime_type = upload.mime_type;
+    media.size_bytes = upload.size_bytes;
+    media.width = upload.width.or(media.width);
+    media.height = upload.height.or(media.height);
+    media.thumbnails = upload.thumbnails.0;
+    media.blurhash = upload.blurhash;
+    Ok(Ok(()))
+}
+
 impl Handler<ClientMessage> for ChatServer {
     type Result = ();
-    fn handle(&mut self, msg: ClientMessage, ctx: &mut Context<Self>) {
-        log::info!("Received message: '{}' from user {}", msg.content, 
-msg.sender_id);
+    fn handle(&mut self, mut msg: ClientMessage, ctx: &mut Context<Self>) {
+        log::info!("Received {:?} message: '{}' from user {}", msg.message_type, msg.content, msg.sender_id);
         let db_pool = self.db_pool.clone();
+        let (session_id, conversation_id, client_message_id) = (msg.session_id, msg.conversation_id, msg.client_message_id);
+        if let Err(reason) = msg.validate() {
+            self.send_to_session(&session_id, &message_error(client_message_id, conversation_id, "invalid_message", reason));
+            return;
+        }
 
         // Spawn a future to insert the message into the database
         let fut = async move {
-            let insert_result = sqlx::query!(
-                "INSERT INTO messages (conversation_id, sender_id, content) VALUES 
-($1, $2, $3)",
+            if let Err(e) = require_participant(&db_pool, msg.conversation_id, msg.sender_id).await {
+                return SendOutcome::Rejected(e);
+            }
+            if let Some(media) = msg.media.as_mut() {
+                match attach_upload(&db_pool, msg.sender_id, msg.message_type, media).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(reason)) => return SendOutcome::Invalid(reason),
+                    Err(e) => { log::error!("Failed to look up upload {}: {}", media.key, e); return SendOutcome::Failed; }
+                }
+            }
+            let media_key = msg.media.as_ref().map(|m| m.key.clone());
+            // A retried send hits the (sender_id, client_message_id) index and inserts nothing
+            let insert_result = sqlx::query_as!(
+                ChatMessage,
+                r#"INSERT INTO messages (conversation_id, sender_id, content, client_message_id, message_type, media) VALUES ($1, $2, $3, $4, $5, $6)
+                   ON CONFLICT (sender_id, client_message_id) DO NOTHING
+                   RETURNING id, conversation_id, sender_id, message_type as "message_type: MessageType", content, status as "status: MessageStatus", media as "media: Json<MediaAttachment>", created_at"#,
                 msg.conversation_id,
                 msg.sender_id,
-                msg.content
+                msg.content,
+                msg.client_message_id,
+                msg.message_type as MessageType,
+                msg.media.map(Json) as Option<Json<MediaAttachment>>
             )
-            .execute(&db_pool)
+            .fetch_optional(&db_pool)
             .await;
 
             match insert_result {
-                Ok(_) => {
+                Ok(Some(saved)) => {
                     log::info!("Message saved to DB successfully.");
-                    // Return the original message to broadcast it
-                    Some(msg)
+                    if let Some(key) = media_key {
+                        if let Err(e) = sqlx::query!("UPDATE media_uploads SET state = 'attached', updated_at = NOW() WHERE object_key = $1", key).execute(&db_pool).await {
+                            log::error!("Failed to mark upload {} attached: {}", key, e);
+                        }
+                    }
+                    // Reload the participants so the fan-out reflects the current membership
+                    let members = sqlx::query_scalar!("SELECT user_id FROM conversation_participants WHERE conversation_id = $1", msg.conversation_id)
+                        .fetch_all(&db_pool)
+                        .await
+                        .map_err(|e| log::error!("Failed to load participants for {}: {}", msg.conversation_id, e))
+                        .ok();
+                    SendOutcome::Saved(saved, members)
                 }
+                Ok(None) => match sqlx::query_as!(
+                    ChatMessage,
+                    r#"SELECT id, conversation_id, sender_id, message_type as "message_type: MessageType", content, status as "status: MessageStatus", media as "media: Json<MediaAttachment>", created_at
+                       FROM messages WHERE sender_id = $1 AND client_message_id = $2"#,
+                    msg.sender_id,
+                    msg.client_message_id
+                )
+                .fetch_one(&db_pool)
+                .await
+                {
+                    Ok(existing) => SendOutcome::Duplicate(existing),
+                    Err(e) => { log::error!("Failed to load duplicate message: {}", e); SendOutcome::Failed }
+                },
                 Err(e) => {
                     log::error!("Failed to save message to DB: {}", e);
-                    // Return None if saving failed
-                    None
+                    SendOutcome::Failed
                 }
             }
         };
 
-        // After the future completes, broadcast the message if it was saved
-        fut.into_actor(self).then(|res, act, _| {
-            if let Some(saved_msg) = res {
-                let response = json!({"event": "new_message", "data": saved_msg});
-                act.broadcast(&saved_msg.conversation_id, &response.to_string(), 
-None);
+        // After the future completes, acknowledge the sender and broadcast the message if it was saved
+        fut.into_actor(self).then(move |res, act, _| {
+            match res {
+                SendOutcome::Saved(saved_msg, members) => {
+                    if let Some(members) = members {
+                        act.conversations.insert(saved_msg.conversation_id, members.into_iter().collect());
+                    }
+                    act.send_to_session(&session_id, &message_ack(client_message_id, &saved_msg, false));
+                    act.deliver_message(&saved_msg);
+                }
+                SendOutcome::Duplicate(existing) => act.send_to_session(&session_id, &message_ack(client_message_id, &existing, true)),
+                SendOutcome::Invalid(reason) => act.send_to_session(&session_id, &message_error(client_message_id, conversation_id, "invalid_message", reason)),
+                SendOutcome::Rejected(e) => {
+                    log::warn!("Rejected message from session {} to {}: {}", session_id, conversation_id, e);
+                    act.send_to_session(&session_id, &message_error(client_message_id, conversation_id, e.code(), &e.to_string()));
+                }
+                SendOutcome::Failed => act.send_to_session(&session_id, &message_error(client_message_id, conversation_id, "internal_error", "The message could not be saved")),
             }
             fut::ready(())
         }).wait(ctx);
     }
 }
 
-// Other handlers remain the same...
+fn message_ack(client_message_id: Option<Uuid>, message: &ChatMessage, duplicate: bool) -> String {
+    json!({"event": "message_ack", "data": {
+        "client_message_id": client_message_id, "id": message.id, "conversation_id": message.conversation_id, "created_at": message.created_at, "duplicate": duplicate
+    }}).to_string()
+}
+
+fn message_error(client_message_id: Option<Uuid>, conversation_id: Uuid, code: &str, message: &str) -> String {
+    json!({"event": "message_error", "data": {"client_message_id": client_message_id, "conversation_id": conversation_id, "code": code, "message": message}}).to_string()
+}
+
 impl Handler<Connect> for ChatServer {
     type Result = ();
     fn handle(&mut self, msg: Connect, ctx: &mut Context<Self>) {
-        self.sessions.insert(msg.user_id, msg.addr);
+        let user_id = msg.user_id;
+        self.sessions.insert(msg.session_id, Session { user_id, device_id: msg.device_id, addr: msg.addr, deliver: msg.deliver, close: msg.close });
+        let devices = self.user_sessions.entry(user_id).or_default();
+        devices.insert(msg.session_id);
+        // Presence only changes when the first device of a user connects
+        let came_online = devices.len() == 1;
         let db_pool = self.db_pool.clone();
-        let fut = async move { sqlx::query!("UPDATE users SET online = TRUE WHERE 
-id = $1", msg.user_id).execute(&db_pool).await };
-        fut.into_actor(self).map(|_, _, _| {}).wait(ctx);
-        let event = json!({"event": "user_online", "data": {"user_id": 
-msg.user_id.to_string()}});
-        self.broadcast(&Uuid::nil(), &event.to_string(), Some(msg.user_id));
+        let fut = async move {
+            if came_online {
+                if let Err(e) = sqlx::query!("UPDATE users SET online = TRUE WHERE id = $1", user_id).execute(&db_pool).await {
+                    log::error!("Failed to mark user {} online: {}", user_id, e);
+                }
+            }
+            sqlx::query!(
+                "SELECT conversation_id, user_id FROM conversation_participants WHERE conversation_id IN (SELECT conversation_id FROM conversation_participants WHERE user_id = $1)",
+                user_id
+            )
+            .fetch_all(&db_pool)
+            .await
+        };
+        fut.into_actor(self).map(move |res, act, _| {
+            match res {
+                Ok(rows) => {
+                    let mut loaded: HashMap<Uuid, HashSet<Uuid>> = HashMap::new();
+                    for row in rows { loaded.entry(row.conversation_id).or_default().insert(row.user_id); }
+                    act.conversations.extend(loaded);
+                }
+                Err(e) => log::error!("Failed to load conversations for user {}: {}", user_id, e),
+            }
+            if came_online {
+                let event = json!({"event": "user_online", "data": {"user_id": user_id.to_string()}});
+                act.send_to_peers(&user_id, &event.to_string());
+            }
+        }).wait(ctx);
     }
 }
 impl Handler<Disconnect> for ChatServer {
     type Result = ();
     fn handle(&mut self, msg: Disconnect, ctx: &mut Context<Self>) {
-        self.sessions.remove(&msg.user_id);
+        let Some(session) = self.sessions.remove(&msg.session_id) else { return };
+        let user_id = session.user_id;
+        if let Some(devices) = self.user_sessions.get_mut(&user_id) {
+            devices.remove(&msg.session_id);
+            // Another device is still connected, so the user stays online
+            if !devices.is_empty() { return; }
+        }
+        self.user_sessions.remove(&user_id);
         let db_pool = self.db_pool.clone();
-        let fut = async move { sqlx::query!("UPDATE users SET online = FALSE, 
-last_seen = NOW() WHERE id = $1", msg.user_id).execute(&db_pool).await };
+        let fut = async move { sqlx::query!("UPDATE users SET online = FALSE, last_seen = NOW() WHERE id = $1", user_id).execute(&db_pool).await };
         fut.into_actor(self).map(|_, _, _| {}).wait(ctx);
-        let event = json!({"event": "user_offline", "data": {"user_id": 
-msg.user_id.to_string()}});
-        self.broadcast(&Uuid::nil(), &event.to_string(), None);
+        let event = json!({"event": "user_offline", "data": {"user_id": user_id.to_string()}});
+        self.send_to_peers(&user_id, &event.to_string());
+        self.prune_conversations();
+    }
+}
+impl Handler<DisconnectDevice> for ChatServer {
+    type Result = ();
+    fn handle(&mut self, msg: DisconnectDevice, _: &mut Context<Self>) {
+        // Sessions clean up after themselves with `Disconnect` once their socket is closed
+        for session in self.sessions.values().filter(|s| s.device_id == msg.device_id) {
+            session.close.do_send(CloseSession { reason: msg.reason });
+        }
+    }
+}
+impl Handler<DeviceLinked> for ChatServer {
+    type Result = ();
+    fn handle(&mut self, msg: DeviceLinked, _: &mut Context<Self>) {
+        self.send_to_user(&msg.user_id, &json!({"event": "device_linked", "data": msg.device}).to_string());
     }
 }
 impl Handler<Typing> for ChatServer {
     type Result = ();
-    fn handle(&mut self, msg: Typing, _: &mut Context<Self>) {
-        let response = json!({"event": "user_typing", "data": msg});
-        self.broadcast(&msg.conversation_id, &response.to_string(), 
-Some(msg.sender_id));
+    fn handle(&mut self, msg: Typing, ctx: &mut Context<Self>) {
+        let db_pool = self.db_pool.clone();
+        let fut = async move { let allowed = require_participant(&db_pool, msg.conversation_id, msg.sender_id).await; (msg, allowed) };
+        fut.into_actor(self).map(|(msg, allowed), act, _| match allowed {
+            Ok(()) => {
+                let response = json!({"event": "user_typing", "data": msg});
+                act.broadcast(&msg.conversation_id, &response.to_string(), Some(msg.sender_id));
+            }
+            Err(e) => act.send_to_session(&msg.session_id, &e.ws_event(msg.conversation_id)),
+        }).spawn(ctx);
     }
 }
+impl Handler<ConversationCreated> for ChatServer {
+    type Result = ();
+    fn handle(&mut self, msg: ConversationCreated, _: &mut Context<Self>) {
+        let conv_id = msg.conversation.conversation.id;
+        self.conversations.insert(conv_id, msg.conversation.participants.iter().map(|p| p.user_id).collect());
+        let event = json!({"event": "conversation_created", "data": msg.conversation}).to_string();
+        // The creator's other devices learn about it here too
+        let members = msg.conversation.participants.iter().flat_map(|p| self.sessions_of(&p.user_id));
+        for session in members.filter(|s| s.device_id != msg.device_id) {
+            session.addr.do_send(WsMessage(event.clone()));
+        }
+    }
+}
+impl Handler<GroupChanged> for ChatServer {
+    type Result = ();
+    fn handle(&mut self, msg: GroupChanged, _: &mut Context<Self>) {
+        self.conversations.insert(msg.conversation_id, msg.members.iter().chain(&msg.removed).copied().collect());
+        for event in &msg.events {
+            self.broadcast(&msg.conversation_id, event, None);
+        }
+        self.conversations.insert(msg.conversation_id, msg.members.into_iter().collect());
+    }
+}
+impl Handler<MediaUpdated> for ChatServer {
+    type Result = ();
+    fn handle(&mut self, msg: MediaUpdated, _: &mut Context<Self>) {
+        let event = json!({"event": "media_updated", "data": {"message_id": msg.message_id, "conversation_id": msg.conversation_id, "media": msg.media}}).to_string();
+        self.broadcast(&msg.conversation_id, &event, None);
+    }
+}
+impl Handler<Delivered> for ChatServer {
+    type Result = ();
+    fn handle(&mut self, msg: Delivered, ctx: &mut Context<Self>) {
+        let db_pool = self.db_pool.clone();
+        let fut = async move {
+            // Only the first delivery to a recipient is reported; later ones are no-ops
+            let receipt = sqlx::query!(
+                "WITH inserted AS (
+                    INSERT INTO message_receipts (message_id, user_id, status) SELECT id, $2, 'delivered' FROM messages WHERE id = $1 AND sender_id <> $2
+                    ON CONFLICT (message_id, user_id) DO NOTHING RETURNING message_id
+                 )
+                 SELECT m.sender_id, m.conversation_id FROM messages m JOIN inserted i ON i.message_id = m.id",
+                msg.message_id,
+                msg.user_id
+            )
+            .fetch_optional(&db_pool)
+            .await?;
+            if receipt.is_some() {
+                refresh_aggregate_status(&db_pool, &[msg.message_id]).await?;
+            }
+            Ok::<_, sqlx::Error>(receipt.map(|r| (r.sender_id, r.conversation_id, msg)))
+        };
+        fut.into_actor(self).map(|res, act, _| match res {
+            Ok(Some((sender_id, conversation_id, msg))) => {
+                let event = json!({"event": "message_status", "data": {"conversation_id": conversation_id, "message_ids": [msg.message_id], "user_id": msg.user_id, "status": MessageStatus::Delivered}});
+                act.send_to_user(&sender_id, &event.to_string());
+            }
+            Ok(None) => {}
+            Err(e) => log::error!("Failed to record delivery receipt: {}", e),
+        }).spawn(ctx);
+    }
+}
+impl Handler<MarkRead> for ChatServer {
+    type Result = ();
+    fn handle(&mut self, msg: MarkRead, ctx: &mut Context<Self>) {
+        let db_pool = self.db_pool.clone();
+        let fut = async move {
+            if let Err(e) = require_participant(&db_pool, msg.conversation_id, msg.user_id).await {
+                return (msg, Err(Some(e)));
+            }
+            // Everything up to and including the given message, in (created_at, id) order, becomes read
+            let read = sqlx::query!(
+                "WITH target AS (SELECT created_at, id FROM messages WHERE id = $3 AND conversation_id = $1),
+                 upserted AS (
+                    INSERT INTO message_receipts (message_id, user_id, status)
+                    SELECT m.id, $2, 'read' FROM messages m, target t
+                    WHERE m.conversation_id = $1 AND m.sender_id <> $2 AND m.message_type <> 'system' AND (m.created_at, m.id) <= (t.created_at, t.id)
+                    ON CONFLICT (message_id, user_id) DO UPDATE SET status = 'read', updated_at = NOW() WHERE message_receipts.status <> 'read'
+                    RETURNING message_id
+                 )
+                 SELECT m.id, m.sender_id FROM messages m JOIN upserted u ON u.message_id = m.id ORDER BY m.created_at, m.id",
+                msg.conversation_id,
+                msg.user_id,
+                msg.up_to_message_id
+            )
+            .fetch_all(&db_pool)
+            .await;
+            let read = match read {
+                Ok(read) => read,
+                Err(e) => { log::error!("Failed to record read receipts: {}", e); return (msg, Err(None)); }
+            };
+            let ids: Vec<Uuid> = read.iter().map(|r| r.id).collect();
+            if let Err(e) = refresh_aggregate_status(&db_pool, &ids).await {
+                log::error!("Failed to refresh message status: {}", e);
+            }
+            let mut by_sender: HashMap<Uuid, Vec<Uuid>> = HashMap::new();
+            for row in read { by_sender.entry(row.sender_id).or_default().push(row.id); }
+            (msg, Ok(by_sender))
+        };
+        fut.into_actor(self).map(|(msg, res), act, _| match res {
+            Ok(by_sender) => {
+                for (sender_id, message_ids) in by_sender {
+                    let event = json!({"event": "message_status", "data": {"conversation_id": msg.conversation_id, "message_ids": message_ids, "user_id": msg.user_id, "status": MessageStatus::Read}});
+                    act.send_to_user(&sender_id, &event.to_string());
+                }
+            }
+            Err(Some(e)) => act.send_to_session(&msg.session_id, &e.ws_event(msg.conversation_id)),
+            Err(None) => {}
+        }).spawn(ctx);
+    }
+}
+
+/// How far before its cursor a sync looks again. A message is stamped when its transaction starts
+/// but only becomes visible when it commits, so one committing just after a sync can carry an
+/// earlier timestamp than the newest one that sync saw.
+const SYNC_OVERLAP: Duration = Duration::seconds(30);
+
+/// An event replayed by `SyncRequest`, ordered by when it happened.
+enum Replay {
+    Message(ChatMessage),
+    Status { conversation_id: Uuid, user_id: Uuid, status: MessageStatus, message_ids: Vec<Uuid> },
+}
+
+impl Handler<SyncRequest> for ChatServer {
+    type Result = ();
+    fn handle(&mut self, msg: SyncRequest, ctx: &mut Context<Self>) {
+        let db_pool = self.db_pool.clone();
+        let (session_id, user_id) = (msg.session_id, msg.user_id);
+        let fut = async move {
+            let conversations = fetch_conversations_of(&db_pool, user_id).await?;
+            let Some(since) = msg.since else {
+                let cursor = sqlx::query_scalar!(r#"SELECT NOW() as "now!""#).fetch_one(&db_pool).await?;
+                return Ok((Vec::new(), cursor, conversations, Vec::new()));
+            };
+            let from = since - SYNC_OVERLAP;
+            // Messages from current conversations, plus those from groups the user left up to the moment they left
+            let messages = sqlx::query_as!(
+                ChatMessage,
+                r#"SELECT m.id, m.conversation_id, m.sender_id, m.message_type as "message_type: MessageType", m.content, m.status as "status: MessageStatus", m.media as "media: Json<MediaAttachment>", m.created_at
+                   FROM messages m
+                   WHERE m.created_at > $2 AND (
+                       m.conversation_id IN (SELECT conversation_id FROM conversation_participants WHERE user_id = $1)
+                       OR EXISTS (SELECT 1 FROM conversation_departures d WHERE d.conversation_id = m.conversation_id AND d.user_id = $1 AND d.departed_at > $2 AND m.created_at <= d.departed_at)
+                   )
+                   ORDER BY m.created_at, m.id"#,
+                user_id,
+                from
+            )
+            .fetch_all(&db_pool)
+            .await?;
+            let receipts = sqlx::query!(
+                r#"SELECT r.message_id, r.user_id, r.status as "status: MessageStatus", r.updated_at, m.conversation_id
+                   FROM message_receipts r JOIN messages m ON m.id = r.message_id
+                   WHERE m.sender_id = $1 AND r.updated_at > $2
+                   ORDER BY r.updated_at, r.message_id"#,
+                user_id,
+                from
+            )
+            .fetch_all(&db_pool)
+            .await?;
+            // Groups the user was removed from or left, unless they are back in them
+            let departures = sqlx::query!(
+                r#"SELECT conversation_id, MAX(departed_at) as "departed_at!" FROM conversation_departures
+                   WHERE user_id = $1 AND departed_at > $2 AND conversation_id NOT IN (SELECT conversation_id FROM conversation_participants WHERE user_id = $1)
+                   GROUP BY conversation_id"#,
+                user_id,
+                from
+            )
+            .fetch_all(&db_pool)
+            .await?;
+            // The cursor only moves as far as rows that were actually read, so nothing committed later is skipped
+            let cursor = messages.iter().map(|m| m.created_at)
+                .chain(receipts.iter().map(|r| r.updated_at))
+                .chain(departures.iter().map(|d| d.departed_at))
+                .fold(since, DateTime::max);
+            let left: Vec<Uuid> = departures.into_iter().map(|d| d.conversation_id).collect();
+
+            let mut replay: Vec<(DateTime<Utc>, Replay)> = messages.into_iter().map(|m| (m.created_at, Replay::Message(m))).collect();
+            for r in receipts {
+                // Receipts written together (e.g. one read event) are replayed as one status event
+                if let Some((_, Replay::Status { conversation_id, user_id, status, message_ids })) = replay.last_mut() {
+                    if *conversation_id == r.conversation_id && *user_id == r.user_id && *status == r.status {
+                        message_ids.push(r.message_id);
+                        continue;
+                    }
+                }
+                replay.push((r.updated_at, Replay::Status { conversation_id: r.conversation_id, user_id: r.user_id, status: r.status, message_ids: vec![r.message_id] }));
+            }
+            replay.sort_by_key(|(at, _)| *at);
+            Ok::<_, sqlx::Error>((replay, cursor, conversations, left))
+        };
+        fut.into_actor(self).map(move |res, act, _| {
+            let Some(session) = act.sessions.get(&session_id) else { return };
+            match res {
+                Ok((replay, cursor, conversations, left)) => {
+                    let count = replay.len();
+                    for (_, item) in replay {
+                        match item {
+                            Replay::Message(message) => {
+                                let payload = json!({"event": "new_message", "data": message}).to_string();
+                                if message.sender_id == user_id {
+                                    session.addr.do_send(WsMessage(payload));
+                                } else {
+                                    session.deliver.do_send(Deliver { message_id: message.id, payload });
+                                }
+                            }
+                            Replay::Status { conversation_id, user_id, status, message_ids } => {
+                                let event = json!({"event": "message_status", "data": {"conversation_id": conversation_id, "message_ids": message_ids, "user_id": user_id, "status": status}});
+                                session.addr.do_send(WsMessage(event.to_string()));
+                            }
+                        }
+                    }
+                    // Conversations are sent whole rather than replayed, so created, renamed and re-membered
+                    // ones are all covered; clients replace their list with them and drop the `left` ones
+                    let event = json!({"event": "sync_complete", "data": {"cursor": cursor, "replayed": count, "conversations": conversations, "left": left}});
+                    session.addr.do_send(WsMessage(event.to_string()));
+                }
+                Err(e) => {
+                    log::error!("Failed to sync user {}: {}", user_id, e);
+                    let event = json!({"event": "error", "data": {"code": "sync_failed", "message": "Could not replay missed events"}});
+                    session.addr.do_send(WsMessage(event.to_string()));
+                }
+            }
+        }).spawn(ctx);
+    }
+}
+
+/// Raises `messages.status` to the lowest state reached by every current recipient.
+async fn refresh_aggregate_status(db_pool: &PgPool, message_ids: &[Uuid]) -> sqlx::Result<()> {
+    sqlx::query!(
+        "UPDATE messages m SET status = agg.status
+         FROM (
+            SELECT m.id, MIN(COALESCE(r.status, 'sent')) AS status
+            FROM messages m
+            JOIN conversation_participants cp ON cp.conversation_id = m.conversation_id AND cp.user_id <> m.sender_id
+            LEFT JOIN message_receipts r ON r.message_id = m.id AND r.user_id = cp.user_id
+            WHERE m.id = ANY($1)
+            GROUP BY m.id
+         ) agg
+         WHERE m.id = agg.id AND m.status < agg.status",
+        message_ids
+    )
+    .execute(db_pool)
+    .await?;
+    Ok(())
+}
@@ -1,8 +1,9 @@
-use crate::actors::server::{ChatServer, ClientMessage, Connect, Disconnect, 
-Typing, WsMessage};
+use crate::actors::server::{ChatServer, ClientMessage, CloseSession, Connect, Deliver, Delivered, Disconnect, MarkRead, SyncRequest, Typing, WsMessage};
 use actix::{fut, Actor, ActorContext, ActorFutureExt, Addr, AsyncContext, 
 ContextFutureSpawner, Handler, Running, StreamHandler, WrapFuture};
+use crate::models::{MediaAttachment, MessageType};
 use actix_web_actors::ws;
+use chrono::{DateTime, Utc};
 use serde::Deserialize;
 use std::time::{Duration, Instant};
 use uuid::Uuid;
@@ -15,18 +16,34 @@ const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
 enum WsClientEvent {
     Message(MessagePayload),
     Typing(TypingPayload),
+    Read(ReadPayload),
+    Sync(SyncPayload),
 }
 
+/// `content` is the text, or the optional caption of an image, video or audio message.
 #[derive(Deserialize, Debug)]
-struct MessagePayload { conversation_id: Uuid, content: String }
+struct MessagePayload {
+    conversation_id: Uuid,
+    client_message_id: Option<Uuid>,
+    #[serde(default)]
+    message_type: MessageType,
+    #[serde(default)]
+    content: String,
+    media: Option<MediaAttachment>,
+}
 #[derive(Deserialize, Debug)]
 struct TypingPayload { conversation_id: Uuid, is_typing: bool }
+#[derive(Deserialize, Debug)]
+struct ReadPayload { conversation_id: Uuid, up_to_message_id: Uuid }
+/// `since` is the `cursor` from the previous `sync_complete`; omit it on a fresh install.
+#[derive(Deserialize, Debug)]
+struct SyncPayload { since: Option<DateTime<Utc>> }
 
-pub struct WebSocketSession { pub user_id: Uuid, pub hb: Instant, pub server_addr: 
-Addr<ChatServer> }
+/// One WebSocket connection. `id` is unique per connection, so a user's devices never collide.
+/// `device_id` is the login whose access token opened it, so logging that device out can close it.
+pub struct WebSocketSession { pub id: Uuid, pub user_id: Uuid, pub device_id: Uuid, pub hb: Instant, pub server_addr: Addr<ChatServer> }
 impl WebSocketSession {
-    pub fn new(user_id: Uuid, server_addr: Addr<ChatServer>) -> Self { Self { 
-user_id, hb: Instant::now(), server_addr } }
+    pub fn new(user_id: Uuid, device_id: Uuid, server_addr: Addr<ChatServer>) -> Self { Self { id: Uuid::new_v4(), user_id, device_id, hb: Instant::now(), server_addr } }
     fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
         ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
             if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT { 
@@ -38,18 +55,31 @@ impl Actor for WebSocketSession {
     type Context = ws::WebsocketContext<Self>;
     fn started(&mut self, ctx: &mut Self::Context) {
         self.hb(ctx);
-        self.server_addr.send(Connect { user_id: self.user_id, addr: 
-ctx.address().recipient() })
+        let addr = ctx.address();
+        self.server_addr.send(Connect { session_id: self.id, user_id: self.user_id, device_id: self.device_id, addr: addr.clone().recipient(), deliver: addr.clone().recipient(), close: addr.recipient() })
             .into_actor(self).then(|r, _, c| { if r.is_err() { c.stop(); } 
 fut::ready(()) }).wait(ctx);
     }
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
-        self.server_addr.do_send(Disconnect { user_id: self.user_id });
+        self.server_addr.do_send(Disconnect { session_id: self.id });
         Running::Stop
     }
 }
-impl Handler<WsMessage> for WebSocketSession { type Result = (); fn handle(&mut 
-self, msg: WsMessage, ctx: &mut Self::Context) { ctx.text(msg.0); } }
+impl Handler<WsMessage> for WebSocketSession { type Result = (); fn handle(&mut self, msg: WsMessage, ctx: &mut Self::Context) { ctx.text(msg.0); } }
+impl Handler<CloseSession> for WebSocketSession {
+    type Result = ();
+    fn handle(&mut self, msg: CloseSession, ctx: &mut Self::Context) {
+        ctx.close(Some(ws::CloseReason { code: ws::CloseCode::Policy, description: Some(msg.reason.to_owned()) }));
+        ctx.stop();
+    }
+}
+impl Handler<Deliver> for WebSocketSession {
+    type Result = ();
+    fn handle(&mut self, msg: Deliver, ctx: &mut Self::Context) {
+        ctx.text(msg.payload);
+        self.server_addr.do_send(Delivered { user_id: self.user_id, message_id: msg.message_id });
+    }
+}
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocketSession {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut 
 Self::Context) {
@@ -59,12 +89,13 @@ ctx.pong(&msg); },
             Ok(ws::Message::Pong(_)) => { self.hb = Instant::now(); },
             Ok(ws::Message::Text(text)) => match 
 serde_json::from_str::<WsClientEvent>(&text) {
-                Ok(WsClientEvent::Message(p)) => 
-self.server_addr.do_send(ClientMessage { sender_id: self.user_id, conversation_id: 
-p.conversation_id, content: p.content }),
-                Ok(WsClientEvent::Typing(p)) => self.server_addr.do_send(Typing { 
-sender_id: self.user_id, conversation_id: p.conversation_id, is_typing: 
-p.is_typing }),
+                Ok(WsClientEvent::Message(p)) => self.server_addr.do_send(ClientMessage {
+                    session_id: self.id, sender_id: self.user_id, conversation_id: p.conversation_id, client_message_id: p.client_message_id,
+                    message_type: p.message_type, content: p.content, media: p.media,
+                }),
+                Ok(WsClientEvent::Typing(p)) => self.server_addr.do_send(Typing { session_id: self.id, sender_id: self.user_id, conversation_id: p.conversation_id, is_typing: p.is_typing }),
+                Ok(WsClientEvent::Read(p)) => self.server_addr.do_send(MarkRead { session_id: self.id, user_id: self.user_id, conversation_id: p.conversation_id, up_to_message_id: p.up_to_message_id }),
+                Ok(WsClientEvent::Sync(p)) => self.server_addr.do_send(SyncRequest { session_id: self.id, user_id: self.user_id, since: p.since }),
                 Err(e) => log::warn!("Unknown WS event from {}: {}", self.user_id, 
 e),
             },
@@ -0,0 +1,129 @@
+use crate::actors::server::{ChatServer, MediaUpdated};
+use crate::models::{MediaAttachment, Thumbnail};
+use crate::storage::MediaStore;
+use actix::{Actor, Addr, AsyncContext, Context, Handler, Message as ActixMessage, WrapFuture};
+use actix_web::web;
+use anyhow::{anyhow, Result};
+use image::{imageops::FilterType, DynamicImage, ImageOutputFormat};
+use sqlx::{types::Json, PgPool};
+use std::io::Cursor;
+use uuid::Uuid;
+
+/// Longest side of each generated thumbnail: one for the conversation list, one for chat bubbles.
+const THUMBNAIL_SIZES: [u32; 2] = [96, 480];
+const BLURHASH_COMPONENTS: (u32, u32) = (4, 3);
+const THUMBNAIL_QUALITY: u8 = 75;
+
+/// Thumbnails are stored next to the original, e.g. `uploads/u/f.jpg` -> `uploads/u/f.jpg.thumb-96.jpg`.
+/// Images with transparency get PNG thumbnails (`.thumb-96.png`) so it survives.
+pub fn thumbnail_key(key: &str, size: u32, extension: &str) -> String { format!("{}.thumb-{}.{}", key, size, extension) }
+
+/// The original a thumbnail key was derived from, so access to it follows access to the original.
+pub fn thumbnail_source(key: &str) -> Option<&str> {
+    let (original, suffix) = key.rsplit_once(".thumb-")?;
+    let size = suffix.strip_suffix(".jpg").or_else(|| suffix.strip_suffix(".png"))?;
+    (!size.is_empty() && size.bytes().all(|b| b.is_ascii_digit())).then_some(original)
+}
+
+/// Sent once an image upload is complete.
+#[derive(ActixMessage, Debug)] #[rtype(result = "()")] pub struct GenerateThumbnails { pub upload_id: Uuid }
+
+/// Builds image previews off the `ChatServer`: decoding and resizing run on the blocking pool,
+/// and messages already carrying the image are patched and re-announced when it is done.
+pub struct Thumbnailer { db_pool: PgPool, store: web::Data<dyn MediaStore>, chat_server: Addr<ChatServer> }
+
+impl Thumbnailer {
+    pub fn new(db_pool: PgPool, store: web::Data<dyn MediaStore>, chat_server: Addr<ChatServer>) -> Self { Self { db_pool, store, chat_server } }
+}
+
+impl Actor for Thumbnailer { type Context = Context<Self>; }
+
+impl Handler<GenerateThumbnails> for Thumbnailer {
+    type Result = ();
+    fn handle(&mut self, msg: GenerateThumbnails, ctx: &mut Context<Self>) {
+        let (db_pool, store, chat_server) = (self.db_pool.clone(), self.store.clone(), self.chat_server.clone());
+        // `spawn` rather than `wait`: uploads are processed concurrently
+        ctx.spawn(
+            async move {
+                if let Err(e) = generate(&db_pool, store.get_ref(), &chat_server, msg.upload_id).await {
+                    log::error!("Thumbnail generation for upload {} failed: {:?}", msg.upload_id, e);
+                }
+            }
+            .into_actor(self),
+        );
+    }
+}
+
+/// `extension` and `mime_type` describe how every thumbnail was encoded.
+struct Previews { width: u32, height: u32, blurhash: String, extension: &'static str, mime_type: &'static str, thumbnails: Vec<(u32, u32, Vec<u8>)> }
+
+/// Whether any pixel is see-through; PNGs often carry an alpha channel that is fully opaque.
+fn has_transparency(image: &DynamicImage) -> bool {
+    image.color().has_alpha() && image.to_rgba8().pixels().any(|p| p.0[3] < u8::MAX)
+}
+
+fn render_previews(bytes: &[u8]) -> Result<Previews> {
+    let image = image::load_from_memory(bytes)?;
+    // JPEG has no alpha channel: transparent stickers would get a black background
+    let transparent = has_transparency(&image);
+    let thumbnails = THUMBNAIL_SIZES
+        .iter()
+        .map(|&size| {
+            // Never upscale: small images get a re-encoded copy at their own size
+            let thumb = if image.width().max(image.height()) > size { image.resize(size, size, FilterType::Triangle) } else { image.clone() };
+            let mut encoded = Cursor::new(Vec::new());
+            if transparent {
+                DynamicImage::ImageRgba8(thumb.to_rgba8()).write_to(&mut encoded, ImageOutputFormat::Png)?;
+            } else {
+                DynamicImage::ImageRgb8(thumb.to_rgb8()).write_to(&mut encoded, ImageOutputFormat::Jpeg(THUMBNAIL_QUALITY))?;
+            }
+            Ok((thumb.width(), thumb.height(), encoded.into_inner()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    // The placeholder only needs a few colours, so hash a tiny copy
+    let tiny = image.thumbnail(32, 32).to_rgba8();
+    let blurhash = blurhash::encode(BLURHASH_COMPONENTS.0, BLURHASH_COMPONENTS.1, tiny.width(), tiny.height(), tiny.as_raw()).map_err(|e| anyhow!("blurhash: {:?}", e))?;
+    let (extension, mime_type) = if transparent { ("png", "image/png") } else { ("jpg", "image/jpeg") };
+    Ok(Previews { width: image.width(), height: image.height(), blurhash, extension, mime_type, thumbnails })
+}
+
+async fn generate(pool: &PgPool, store: &dyn MediaStore, chat_server: &Addr<ChatServer>, upload_id: Uuid) -> Result<()> {
+    let Some(upload) = sqlx::query!("SELECT object_key FROM media_uploads WHERE id = $1 AND kind = 'image' AND state IN ('uploaded', 'attached')", upload_id).fetch_optional(pool).await? else {
+        return Ok(());
+    };
+    let bytes = store.get(&upload.object_key).await?.ok_or_else(|| anyhow!("{} is missing from the store", upload.object_key))?;
+    let previews = web::block(move || render_previews(&bytes)).await??;
+
+    let mut thumbnails = Vec::with_capacity(previews.thumbnails.len());
+    for (size, (width, height, encoded)) in THUMBNAIL_SIZES.iter().zip(previews.thumbnails) {
+        let key = thumbnail_key(&upload.object_key, *size, previews.extension);
+        store.put(&key, previews.mime_type, encoded).await?;
+        thumbnails.push(Thumbnail { key, width: width as i32, height: height as i32 });
+    }
+    let (width, height) = (previews.width as i32, previews.height as i32);
+    sqlx::query!(
+        "UPDATE media_uploads SET width = $2, height = $3, thumbnails = $4, blurhash = $5, updated_at = NOW() WHERE id = $1",
+        upload_id,
+        width,
+        height,
+        Json(&thumbnails) as _,
+        previews.blurhash
+    )
+    .execute(pool)
+    .await?;
+
+    // The image may already have been sent before its previews were ready
+    let patch = serde_json::json!({"width": width, "height": height, "thumbnails": thumbnails, "blurhash": previews.blurhash});
+    let updated = sqlx::query!(
+        r#"UPDATE messages SET media = media || $2 WHERE media->>'key' = $1 RETURNING id, conversation_id, media as "media!: Json<MediaAttachment>""#,
+        upload.object_key,
+        patch
+    )
+    .fetch_all(pool)
+    .await?;
+    for message in updated {
+        chat_server.do_send(MediaUpdated { conversation_id: message.conversation_id, message_id: message.id, media: message.media.0 });
+    }
+    log::info!("Generated {} thumbnails for upload {}", thumbnails.len(), upload_id);
+    Ok(())
+}
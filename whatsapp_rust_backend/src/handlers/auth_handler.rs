@@ -1,33 +1,119 @@
-use crate::{models::{AuthResponse, SendOtpRequest, User, VerifyOtpRequest}, utils::jwt::create_jwt};
-use actix_web::{web, HttpResponse, Responder};
+use crate::{actors::server::{ChatServer, DisconnectDevice}, models::{RefreshRequest, SendOtpRequest, User, VerifyOtpRequest}, otp::{policy::{OtpError, OtpPolicy}, OtpChannel, OtpSender}, utils::{phone::normalize_phone, refresh_tokens::{self, NewDevice, RefreshError}}};
+use actix::Addr;
+use actix_web::{web, HttpRequest, HttpResponse, Responder, ResponseError};
 use bcrypt::{hash, verify, DEFAULT_COST};
+use chrono::{Duration, Utc};
 use rand::{thread_rng, Rng};
 use serde_json::json;
 use sqlx::PgPool;
-pub async fn send_otp(pool: web::Data<PgPool>, req: web::Json<SendOtpRequest>) -> impl Responder {
+pub async fn send_otp(pool: web::Data<PgPool>, sender: web::Data<dyn OtpSender>, policy: web::Data<OtpPolicy>, http_req: HttpRequest, req: web::Json<SendOtpRequest>) -> impl Responder {
+    let mut req = req.into_inner();
+    req.phone_number = match normalize_phone(&req.phone_number) { Ok(phone_number) => phone_number, Err(e) => return e.error_response() };
+    let ip_address = policy.client_ip(&http_req);
+    match issue_otp(pool.get_ref(), sender.get_ref(), &policy, &ip_address, &req).await {
+        Ok(()) => HttpResponse::Ok().json(json!({"status": "success", "phone_number": req.phone_number, "channel": req.channel, "expires_in": policy.lifetime_secs, "resend_after": policy.phone_cooldown_secs})),
+        Err(e) => e.error_response(),
+    }
+}
+/// Enforces the resend limits, stores a fresh code (resetting its attempts) and delivers it.
+async fn issue_otp(pool: &PgPool, sender: &dyn OtpSender, policy: &OtpPolicy, ip_address: &str, req: &SendOtpRequest) -> Result<(), OtpError> {
+    if req.channel == OtpChannel::Voice && !sender.supports_voice() { return Err(OtpError::VoiceUnavailable); }
+    // Checked up front too, so requests that are bound to be refused do not pay for hashing a code
+    if let Some(retry_after_secs) = cooldown_left(pool, policy, &req.phone_number).await? {
+        return Err(OtpError::ResendTooSoon { retry_after_secs });
+    }
+    let window_start = Utc::now() - Duration::seconds(policy.ip_window_secs);
+    let ip_sends = sqlx::query!(r#"SELECT COUNT(*) as "count!", MIN(sent_at) as oldest FROM otp_sends WHERE ip_address = $1 AND sent_at > $2"#, ip_address, window_start).fetch_one(pool).await?;
+    if ip_sends.count >= policy.ip_max_sends {
+        let retry_after_secs = ip_sends.oldest.map_or(policy.ip_window_secs, |t| (t - window_start).num_seconds().max(1));
+        return Err(OtpError::IpRateLimited { retry_after_secs });
+    }
     let otp = format!("{:06}", thread_rng().gen_range(0..=999_999));
-    let otp_hash = match hash(&otp, DEFAULT_COST) { Ok(h) => h, Err(_) => return HttpResponse::InternalServerError().finish() };
-    match sqlx::query!("INSERT INTO temp_otps (phone_number, otp_hash, created_at) VALUES ($1, $2, NOW()) ON CONFLICT (phone_number) DO UPDATE SET otp_hash = $2, created_at = NOW()", req.phone_number, otp_hash)
-        .execute(pool.get_ref()).await {
-        Ok(_) => { log::info!("OTP for {}: {}", req.phone_number, otp); HttpResponse::Ok().json(json!({"status": "success"})) },
-        Err(e) => { log::error!("Failed to save OTP: {}", e); HttpResponse::InternalServerError().finish() }
-    }
-}
-pub async fn verify_otp(pool: web::Data<PgPool>, req: web::Json<VerifyOtpRequest>) -> impl Responder {
-    match sqlx::query!("SELECT otp_hash FROM temp_otps WHERE phone_number = $1", req.phone_number).fetch_optional(pool.get_ref()).await {
-        Ok(Some(record)) => if verify(&req.otp, &record.otp_hash).unwrap_or(false) {
-            // THE FIX IS HERE: Added 'name' to the SELECT statement
-            let user_res = sqlx::query_as!(User, "SELECT id, phone_number, name FROM users WHERE phone_number = $1", req.phone_number).fetch_optional(pool.get_ref()).await;
-            let user_id = match user_res {
-                Ok(Some(user)) => user.id,
-                Ok(None) => sqlx::query!("INSERT INTO users (phone_number) VALUES ($1) RETURNING id", req.phone_number).fetch_one(pool.get_ref()).await.unwrap().id,
-                Err(_) => return HttpResponse::InternalServerError().finish(),
-            };
-            sqlx::query!("DELETE FROM temp_otps WHERE phone_number = $1", req.phone_number).execute(pool.get_ref()).await.ok();
-            let token = create_jwt(&user_id.to_string()).unwrap();
-            HttpResponse::Ok().json(AuthResponse { token, user_id: user_id.to_string() })
-        } else { HttpResponse::Unauthorized().json(json!({"message": "Invalid OTP"})) },
-        _ => HttpResponse::NotFound().json(json!({"message": "OTP not found or expired"}))
-    }
-}
-pub fn config(cfg: &mut web::ServiceConfig) { cfg.service(web::resource("/auth/send-otp").route(web::post().to(send_otp))).service(web::resource("/auth/verify-otp").route(web::post().to(verify_otp))); }
\ No newline at end of file
+    let otp_hash = hash(&otp, DEFAULT_COST).map_err(|e| { log::error!("Failed to hash OTP: {}", e); OtpError::DeliveryFailed })?;
+    // The cooldown is enforced by this statement alone: of concurrent sends, only one replaces the code
+    let stored = sqlx::query!(
+        "INSERT INTO temp_otps (phone_number, otp_hash, created_at) VALUES ($1, $2, NOW())
+         ON CONFLICT (phone_number) DO UPDATE SET otp_hash = $2, created_at = NOW(), attempts = 0 WHERE temp_otps.created_at <= NOW() - make_interval(secs => $3)",
+        req.phone_number,
+        otp_hash,
+        policy.phone_cooldown_secs as f64
+    )
+    .execute(pool)
+    .await?;
+    if stored.rows_affected() == 0 {
+        let retry_after_secs = cooldown_left(pool, policy, &req.phone_number).await?.unwrap_or(1);
+        return Err(OtpError::ResendTooSoon { retry_after_secs });
+    }
+    // Logged before delivery so a failing gateway cannot be hammered either
+    sqlx::query!("INSERT INTO otp_sends (phone_number, ip_address) VALUES ($1, $2)", req.phone_number, ip_address).execute(pool).await?;
+    sender.send(req.channel, &req.phone_number, &otp).await.map_err(|e| { log::error!("Failed to deliver OTP to {} by {}: {:?}", req.phone_number, req.channel, e); OtpError::DeliveryFailed })
+}
+/// Seconds until another code may be sent to `phone_number`, if it is still cooling down. The
+/// current code's row is kept until it is used or expires, so a locked code still counts.
+async fn cooldown_left(pool: &PgPool, policy: &OtpPolicy, phone_number: &str) -> Result<Option<i64>, OtpError> {
+    let sent_at = sqlx::query_scalar!("SELECT created_at FROM temp_otps WHERE phone_number = $1", phone_number).fetch_optional(pool).await?;
+    Ok(sent_at.map(|t| policy.phone_cooldown_secs - (Utc::now() - t).num_seconds()).filter(|s| *s > 0))
+}
+pub async fn verify_otp(pool: web::Data<PgPool>, chat_server: web::Data<Addr<ChatServer>>, policy: web::Data<OtpPolicy>, http_req: HttpRequest, req: web::Json<VerifyOtpRequest>) -> impl Responder {
+    // Codes are stored under the normalized number, so "+1 415 555 2671" and "4155552671" log into the same account
+    let mut req = req.into_inner();
+    req.phone_number = match normalize_phone(&req.phone_number) { Ok(phone_number) => phone_number, Err(e) => return e.error_response() };
+    if let Err(e) = check_otp(pool.get_ref(), &policy, &req).await { return e.error_response(); }
+    // THE FIX IS HERE: Added 'name' to the SELECT statement
+    let user_res = sqlx::query_as!(User, "SELECT id, phone_number, name FROM users WHERE phone_number = $1", req.phone_number).fetch_optional(pool.get_ref()).await;
+    let user_id = match user_res {
+        Ok(Some(user)) => user.id,
+        Ok(None) => sqlx::query!("INSERT INTO users (phone_number) VALUES ($1) RETURNING id", req.phone_number).fetch_one(pool.get_ref()).await.unwrap().id,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let device = NewDevice::describe(req.device_name.as_deref(), req.platform, &http_req);
+    match refresh_tokens::sign_in(pool.get_ref(), &chat_server, user_id, device).await {
+        Ok(auth) => HttpResponse::Ok().json(auth),
+        Err(e) => { log::error!("Failed to sign in user {}: {:?}", user_id, e); HttpResponse::InternalServerError().finish() }
+    }
+}
+/// Rotates the refresh token: the response carries a new access token and the token to use next time.
+pub async fn refresh(pool: web::Data<PgPool>, chat_server: web::Data<Addr<ChatServer>>, req: web::Json<RefreshRequest>) -> impl Responder {
+    match refresh_tokens::rotate(pool.get_ref(), &req.refresh_token).await {
+        Ok(auth) => HttpResponse::Ok().json(auth),
+        Err(e) => {
+            if let RefreshError::Reused { device_id } = e { chat_server.do_send(DisconnectDevice { device_id, reason: "session_revoked" }); }
+            e.error_response()
+        }
+    }
+}
+/// Revokes the device's refresh tokens and closes its WebSocket connections. Access tokens it
+/// already holds stay valid until they expire, which is why they are short-lived.
+pub async fn logout(pool: web::Data<PgPool>, chat_server: web::Data<Addr<ChatServer>>, req: web::Json<RefreshRequest>) -> impl Responder {
+    match refresh_tokens::revoke(pool.get_ref(), &req.refresh_token).await {
+        Ok(Some(device_id)) => { chat_server.do_send(DisconnectDevice { device_id, reason: "logged_out" }); HttpResponse::NoContent().finish() }
+        Ok(None) => RefreshError::Invalid.error_response(),
+        Err(e) => RefreshError::Database(e).error_response(),
+    }
+}
+/// Consumes the code if it matches. Each guess is counted before it is checked, so parallel guesses
+/// cannot exceed `max_attempts`, and only the request that deletes the code signs in with it. A code
+/// whose attempts ran out stays locked until a new one is sent, so the resend cooldown still applies.
+async fn check_otp(pool: &PgPool, policy: &OtpPolicy, req: &VerifyOtpRequest) -> Result<(), OtpError> {
+    let claimed = sqlx::query!("UPDATE temp_otps SET attempts = attempts + 1 WHERE phone_number = $1 AND attempts < $2 RETURNING otp_hash, created_at, attempts", req.phone_number, policy.max_attempts)
+        .fetch_optional(pool).await?;
+    // No row left to claim: either no code was requested, or its attempts are used up
+    let Some(otp) = claimed else {
+        let locked = sqlx::query_scalar!(r#"SELECT EXISTS(SELECT 1 FROM temp_otps WHERE phone_number = $1) as "exists!""#, req.phone_number).fetch_one(pool).await?;
+        return Err(if locked { OtpError::Locked } else { OtpError::NotFound });
+    };
+    // Deletes this code only, not one sent since it was claimed, and tells whether this request did
+    let consume = || async {
+        sqlx::query!("DELETE FROM temp_otps WHERE phone_number = $1 AND otp_hash = $2", req.phone_number, otp.otp_hash).execute(pool).await.map(|r| r.rows_affected() > 0)
+    };
+    if (Utc::now() - otp.created_at).num_seconds() >= policy.lifetime_secs { consume().await?; return Err(OtpError::Expired); }
+    if verify(&req.otp, &otp.otp_hash).unwrap_or(false) {
+        return if consume().await? { Ok(()) } else { Err(OtpError::NotFound) };
+    }
+    if otp.attempts >= policy.max_attempts { return Err(OtpError::Locked); }
+    Err(OtpError::Invalid { attempts_remaining: policy.max_attempts - otp.attempts })
+}
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/auth/send-otp").route(web::post().to(send_otp))).service(web::resource("/auth/verify-otp").route(web::post().to(verify_otp)))
+        .service(web::resource("/auth/refresh").route(web::post().to(refresh))).service(web::resource("/auth/logout").route(web::post().to(logout)));
+}
@@ -1,6 +1,11 @@
-use crate::models::{Claims, ChatMessage, ConversationDetails};
-use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
-use sqlx::{types::Uuid, PgPool};
+use crate::actors::server::{ChatServer, ConversationCreated};
+use crate::models::{Claims, ChatMessage, Conversation, ConversationDetails, ConversationWithParticipants, CreateConversationRequest, MediaAttachment, MessageHistoryQuery, MessagePage, MessageStatus, MessageType, Participant};
+use crate::utils::authz::require_participant;
+use actix::Addr;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder, ResponseError};
+use serde_json::json;
+use sqlx::{types::{Json, Uuid}, PgPool};
+use std::collections::HashMap;
 
 pub async fn get_conversations(pool: web::Data<PgPool>, req: HttpRequest) -> impl 
 Responder {
@@ -36,20 +41,72 @@ HttpResponse::InternalServerError().finish() }
 }
 
 // --- ADD NEW HANDLER FOR MESSAGE HISTORY ---
-pub async fn get_message_history(pool: web::Data<PgPool>, path: web::Path<Uuid>) 
--> impl Responder {
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 100;
+
+pub async fn get_message_history(pool: web::Data<PgPool>, req: HttpRequest, path: web::Path<Uuid>, query: web::Query<MessageHistoryQuery>) -> impl Responder {
+    let user_id = Uuid::parse_str(&req.extensions().get::<Claims>().unwrap().sub).unwrap();
     let conversation_id = path.into_inner();
-    let query_result = sqlx::query_as!(
-        ChatMessage,
-        "SELECT id, conversation_id, sender_id, content, created_at FROM messages 
-WHERE conversation_id = $1 ORDER BY created_at ASC",
-        conversation_id
-    )
-    .fetch_all(pool.get_ref())
-    .await;
+    if let Err(e) = require_participant(pool.get_ref(), conversation_id, user_id).await {
+        return e.error_response();
+    }
+    if query.before.is_some() && query.after.is_some() {
+        return HttpResponse::BadRequest().json(json!({"message": "Use either before or after, not both"}));
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    // Resolve the cursor message to its (created_at, id) key within this conversation
+    let cursor = match query.before.or(query.after) {
+        Some(cursor_id) => match sqlx::query_scalar!("SELECT created_at FROM messages WHERE id = $1 AND conversation_id = $2", cursor_id, conversation_id)
+            .fetch_optional(pool.get_ref())
+            .await
+        {
+            Ok(Some(created_at)) => Some((created_at, cursor_id)),
+            Ok(None) => return HttpResponse::BadRequest().json(json!({"message": "Unknown cursor"})),
+            Err(e) => { log::error!("Failed to resolve history cursor: {}", e); return HttpResponse::InternalServerError().finish(); }
+        },
+        None => None,
+    };
+
+    // One extra row tells us whether another page exists
+    let query_result = match (query.after, cursor) {
+        (Some(_), Some((created_at, cursor_id))) => sqlx::query_as!(
+            ChatMessage,
+            r#"SELECT id, conversation_id, sender_id, message_type as "message_type: MessageType", content, status as "status: MessageStatus", media as "media: Json<MediaAttachment>", created_at
+               FROM messages WHERE conversation_id = $1 AND (created_at, id) > ($2, $3) ORDER BY created_at ASC, id ASC LIMIT $4"#,
+            conversation_id,
+            created_at,
+            cursor_id,
+            limit + 1
+        )
+        .fetch_all(pool.get_ref())
+        .await,
+        _ => sqlx::query_as!(
+            ChatMessage,
+            r#"SELECT id, conversation_id, sender_id, message_type as "message_type: MessageType", content, status as "status: MessageStatus", media as "media: Json<MediaAttachment>", created_at
+               FROM messages WHERE conversation_id = $1 AND ($2::timestamptz IS NULL OR (created_at, id) < ($2, $3::uuid)) ORDER BY created_at DESC, id DESC LIMIT $4"#,
+            conversation_id,
+            cursor.map(|(created_at, _)| created_at),
+            cursor.map(|(_, cursor_id)| cursor_id),
+            limit + 1
+        )
+        .fetch_all(pool.get_ref())
+        .await
+        .map(|mut messages| { messages.reverse(); messages }),
+    };
 
     match query_result {
-        Ok(messages) => HttpResponse::Ok().json(messages),
+        Ok(mut messages) => {
+            let has_more = messages.len() as i64 > limit;
+            let next_cursor = if query.after.is_some() {
+                messages.truncate(limit as usize);
+                messages.last().filter(|_| has_more).map(|m| m.id)
+            } else {
+                if has_more { messages.remove(0); }
+                messages.first().filter(|_| has_more).map(|m| m.id)
+            };
+            HttpResponse::Ok().json(MessagePage { messages, next_cursor })
+        }
         Err(e) => {
             log::error!("Failed to fetch message history: {}", e);
             HttpResponse::InternalServerError().finish()
@@ -57,9 +114,137 @@ WHERE conversation_id = $1 ORDER BY created_at ASC",
     }
 }
 
+pub async fn create_conversation(pool: web::Data<PgPool>, srv: web::Data<Addr<ChatServer>>, req: HttpRequest, body: web::Json<CreateConversationRequest>) -> impl Responder {
+    let (user_id, device_id) = {
+        let extensions = req.extensions();
+        let claims = extensions.get::<Claims>().unwrap();
+        (Uuid::parse_str(&claims.sub).unwrap(), claims.device_id)
+    };
+    let body = body.into_inner();
+    let mut others: Vec<Uuid> = body.participant_ids.into_iter().filter(|id| *id != user_id).collect();
+    others.sort();
+    others.dedup();
+    let group_name = body.group_name.as_deref().map(str::trim).filter(|name| !name.is_empty());
+    if !body.is_group && others.len() != 1 {
+        return HttpResponse::BadRequest().json(json!({"message": "A direct conversation needs exactly one other participant"}));
+    }
+    if body.is_group && group_name.is_none() {
+        return HttpResponse::BadRequest().json(json!({"message": "A group conversation needs a group_name"}));
+    }
+    match sqlx::query_scalar!(r#"SELECT COUNT(*) as "count!" FROM users WHERE id = ANY($1)"#, &others).fetch_one(pool.get_ref()).await {
+        Ok(count) if count as usize == others.len() => {}
+        Ok(_) => return HttpResponse::NotFound().json(json!({"message": "One or more participants do not exist"})),
+        Err(e) => { log::error!("Failed to look up participants: {}", e); return HttpResponse::InternalServerError().finish(); }
+    }
+
+    let created = if body.is_group {
+        create_group(pool.get_ref(), user_id, &others, group_name, body.group_icon_url.as_deref()).await.map(|id| (id, true))
+    } else {
+        find_or_create_direct(pool.get_ref(), user_id, others[0]).await
+    };
+    let (conversation_id, is_new) = match created {
+        Ok(created) => created,
+        Err(e) => { log::error!("Failed to create conversation: {}", e); return HttpResponse::InternalServerError().finish(); }
+    };
+    match fetch_conversation(pool.get_ref(), conversation_id).await {
+        Ok(conversation) if is_new => {
+            srv.do_send(ConversationCreated { device_id, conversation: conversation.clone() });
+            HttpResponse::Created().json(conversation)
+        }
+        Ok(conversation) => HttpResponse::Ok().json(conversation),
+        Err(e) => { log::error!("Failed to load conversation {}: {}", conversation_id, e); HttpResponse::InternalServerError().finish() }
+    }
+}
+
+/// Returns the existing direct chat between the two users, creating it if needed.
+async fn find_or_create_direct(pool: &PgPool, user_id: Uuid, other_id: Uuid) -> sqlx::Result<(Uuid, bool)> {
+    let mut tx = pool.begin().await?;
+    // Serialise concurrent requests for the same pair so only one direct chat is ever created
+    let (low, high) = if user_id < other_id { (user_id, other_id) } else { (other_id, user_id) };
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtextextended($1, 0))").bind(format!("direct:{}:{}", low, high)).execute(&mut *tx).await?;
+    let existing = sqlx::query_scalar!(
+        "SELECT c.id FROM conversations c
+         JOIN conversation_participants a ON a.conversation_id = c.id AND a.user_id = $1
+         JOIN conversation_participants b ON b.conversation_id = c.id AND b.user_id = $2
+         WHERE NOT c.is_group LIMIT 1",
+        user_id,
+        other_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    if let Some(id) = existing {
+        tx.commit().await?;
+        return Ok((id, false));
+    }
+    let id = sqlx::query_scalar!("INSERT INTO conversations (is_group) VALUES (FALSE) RETURNING id").fetch_one(&mut *tx).await?;
+    sqlx::query!("INSERT INTO conversation_participants (conversation_id, user_id) SELECT $1, UNNEST($2::uuid[])", id, &[user_id, other_id][..])
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok((id, true))
+}
+
+async fn create_group(pool: &PgPool, creator_id: Uuid, others: &[Uuid], group_name: Option<&str>, group_icon_url: Option<&str>) -> sqlx::Result<Uuid> {
+    let mut tx = pool.begin().await?;
+    let id = sqlx::query_scalar!("INSERT INTO conversations (is_group, group_name, group_icon_url) VALUES (TRUE, $1, $2) RETURNING id", group_name, group_icon_url)
+        .fetch_one(&mut *tx)
+        .await?;
+    let members: Vec<Uuid> = std::iter::once(creator_id).chain(others.iter().copied()).collect();
+    sqlx::query!(
+        "INSERT INTO conversation_participants (conversation_id, user_id, is_admin) SELECT $1, member, member = $2 FROM UNNEST($3::uuid[]) AS member",
+        id,
+        creator_id,
+        &members
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(id)
+}
+
+/// Loads a conversation together with its current participants.
+pub(crate) async fn fetch_conversation(pool: &PgPool, conversation_id: Uuid) -> sqlx::Result<ConversationWithParticipants> {
+    let conversation = sqlx::query_as!(Conversation, "SELECT id, is_group, group_name, group_icon_url, created_at FROM conversations WHERE id = $1", conversation_id)
+        .fetch_one(pool)
+        .await?;
+    let participants = sqlx::query_as!(
+        Participant,
+        "SELECT cp.user_id, u.name, cp.is_admin FROM conversation_participants cp JOIN users u ON u.id = cp.user_id WHERE cp.conversation_id = $1 ORDER BY cp.is_admin DESC, u.name",
+        conversation_id
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(ConversationWithParticipants { conversation, participants })
+}
+
+/// Every conversation `user_id` is a member of, oldest first, with its participants.
+pub(crate) async fn fetch_conversations_of(pool: &PgPool, user_id: Uuid) -> sqlx::Result<Vec<ConversationWithParticipants>> {
+    let conversations = sqlx::query_as!(
+        Conversation,
+        "SELECT c.id, c.is_group, c.group_name, c.group_icon_url, c.created_at FROM conversations c JOIN conversation_participants cp ON cp.conversation_id = c.id WHERE cp.user_id = $1 ORDER BY c.created_at, c.id",
+        user_id
+    )
+    .fetch_all(pool)
+    .await?;
+    let rows = sqlx::query!(
+        r#"SELECT cp.conversation_id, cp.user_id, u.name, cp.is_admin FROM conversation_participants cp JOIN users u ON u.id = cp.user_id
+           WHERE cp.conversation_id IN (SELECT conversation_id FROM conversation_participants WHERE user_id = $1) ORDER BY cp.is_admin DESC, u.name"#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut participants: HashMap<Uuid, Vec<Participant>> = HashMap::new();
+    for r in rows {
+        participants.entry(r.conversation_id).or_default().push(Participant { user_id: r.user_id, name: r.name, is_admin: r.is_admin });
+    }
+    Ok(conversations.into_iter().map(|conversation| {
+        let participants = participants.remove(&conversation.id).unwrap_or_default();
+        ConversationWithParticipants { conversation, participants }
+    }).collect())
+}
+
 pub fn config(cfg: &mut web::ServiceConfig) {
-    
-cfg.service(web::resource("/conversations").route(web::get().to(get_conversations)))
+    cfg.service(web::resource("/conversations").route(web::get().to(get_conversations)).route(web::post().to(create_conversation)))
        
 .service(web::resource("/conversations/{id}/messages").route(web::get().to(get_message_history)));
 }
@@ -0,0 +1,51 @@
+use crate::actors::server::{ChatServer, DisconnectDevice};
+use crate::models::{Claims, Device, DevicePlatform};
+use crate::utils::refresh_tokens::revoke_device;
+use actix::Addr;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use serde_json::json;
+use sqlx::{types::Uuid, PgPool};
+
+/// The caller's signed-in devices, most recently active first.
+pub async fn get_devices(pool: web::Data<PgPool>, req: HttpRequest) -> impl Responder {
+    let (user_id, device_id) = {
+        let extensions = req.extensions();
+        let claims = extensions.get::<Claims>().unwrap();
+        (Uuid::parse_str(&claims.sub).unwrap(), claims.device_id)
+    };
+    let devices = sqlx::query_as!(
+        Device,
+        r#"SELECT id, name, platform as "platform: DevicePlatform", linked_at, last_active_at, id = $2 as "current!"
+           FROM devices WHERE user_id = $1 ORDER BY last_active_at DESC"#,
+        user_id,
+        device_id
+    )
+    .fetch_all(pool.get_ref())
+    .await;
+    match devices {
+        Ok(devices) => HttpResponse::Ok().json(devices),
+        Err(e) => { log::error!("Failed to fetch devices: {}", e); HttpResponse::InternalServerError().finish() }
+    }
+}
+
+/// Signs one of the caller's devices out: its tokens stop working and its live connections are closed.
+pub async fn delete_device(pool: web::Data<PgPool>, chat_server: web::Data<Addr<ChatServer>>, req: HttpRequest, path: web::Path<Uuid>) -> impl Responder {
+    let user_id = Uuid::parse_str(&req.extensions().get::<Claims>().unwrap().sub).unwrap();
+    let device_id = path.into_inner();
+    match sqlx::query_scalar!(r#"SELECT EXISTS(SELECT 1 FROM devices WHERE id = $1 AND user_id = $2) as "exists!""#, device_id, user_id).fetch_one(pool.get_ref()).await {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::NotFound().json(json!({"code": "device_not_found", "message": "You have no device with this id"})),
+        Err(e) => { log::error!("Failed to look up device {}: {}", device_id, e); return HttpResponse::InternalServerError().finish(); }
+    }
+    if let Err(e) = revoke_device(pool.get_ref(), device_id).await {
+        log::error!("Failed to revoke device {}: {}", device_id, e);
+        return HttpResponse::InternalServerError().finish();
+    }
+    chat_server.do_send(DisconnectDevice { device_id, reason: "device_removed" });
+    HttpResponse::NoContent().finish()
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/devices").route(web::get().to(get_devices)))
+       .service(web::resource("/devices/{device_id}").route(web::delete().to(delete_device)));
+}
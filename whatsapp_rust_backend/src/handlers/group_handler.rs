@@ -0,0 +1,294 @@
+use crate::actors::server::{ChatServer, GroupChanged};
+use crate::handlers::conversation_handler::fetch_conversation;
+use crate::models::{AddParticipantsRequest, ChatMessage, Claims, MediaAttachment, MessageStatus, MessageType, SetAdminRequest, UpdateGroupRequest};
+use crate::utils::authz::{group_role, AuthzError};
+use actix::Addr;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder, ResponseError};
+use serde_json::{json, Value};
+use sqlx::{types::{Json, Uuid}, PgConnection, PgPool};
+use std::collections::HashMap;
+
+pub async fn add_participants(pool: web::Data<PgPool>, srv: web::Data<Addr<ChatServer>>, req: HttpRequest, path: web::Path<Uuid>, body: web::Json<AddParticipantsRequest>) -> impl Responder {
+    let actor_id = Uuid::parse_str(&req.extensions().get::<Claims>().unwrap().sub).unwrap();
+    let conversation_id = path.into_inner();
+    let mut user_ids = body.into_inner().user_ids;
+    user_ids.sort();
+    user_ids.dedup();
+
+    let result = async {
+        let mut tx = pool.begin().await?;
+        lock_as_admin(&mut tx, conversation_id, actor_id).await?;
+        let known = sqlx::query_scalar!(r#"SELECT COUNT(*) as "count!" FROM users WHERE id = ANY($1)"#, &user_ids).fetch_one(&mut *tx).await?;
+        if known as usize != user_ids.len() {
+            return Ok(None);
+        }
+        let added = sqlx::query_scalar!(
+            "INSERT INTO conversation_participants (conversation_id, user_id) SELECT $1, UNNEST($2::uuid[]) ON CONFLICT DO NOTHING RETURNING user_id",
+            conversation_id,
+            &user_ids
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        let mut messages = Vec::new();
+        if !added.is_empty() {
+            let names = display_names(&mut tx, &[&[actor_id][..], &added].concat()).await?;
+            let added_names: Vec<&str> = added.iter().map(|id| name_of(&names, id)).collect();
+            let content = format!("{} added {}", name_of(&names, &actor_id), added_names.join(", "));
+            messages.push(insert_system_message(&mut tx, conversation_id, actor_id, &content).await?);
+        }
+        tx.commit().await?;
+        Ok::<_, AuthzError>(Some((added, messages)))
+    }
+    .await;
+    match result {
+        Ok(None) => HttpResponse::NotFound().json(json!({"message": "One or more users do not exist"})),
+        Ok(Some((added, _))) if added.is_empty() => respond_with_conversation(pool.get_ref(), conversation_id).await,
+        Ok(Some((added, messages))) => notify(pool.get_ref(), &srv, conversation_id, "participants_added", json!({"actor_id": actor_id, "user_ids": added}), Vec::new(), messages).await,
+        Err(AuthzError::Database(e)) => { log::error!("Failed to add participants to {}: {}", conversation_id, e); HttpResponse::InternalServerError().finish() }
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn remove_participant(pool: web::Data<PgPool>, srv: web::Data<Addr<ChatServer>>, req: HttpRequest, path: web::Path<(Uuid, Uuid)>) -> impl Responder {
+    let actor_id = Uuid::parse_str(&req.extensions().get::<Claims>().unwrap().sub).unwrap();
+    let (conversation_id, user_id) = path.into_inner();
+    if user_id == actor_id {
+        return HttpResponse::BadRequest().json(json!({"message": "Use the leave endpoint to leave a group"}));
+    }
+
+    let result = async {
+        let mut tx = pool.begin().await?;
+        lock_as_admin(&mut tx, conversation_id, actor_id).await?;
+        let removed = sqlx::query!("DELETE FROM conversation_participants WHERE conversation_id = $1 AND user_id = $2", conversation_id, user_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        if removed == 0 {
+            return Ok(None);
+        }
+        record_departure(&mut tx, conversation_id, user_id).await?;
+        let names = display_names(&mut tx, &[actor_id, user_id]).await?;
+        let message = insert_system_message(&mut tx, conversation_id, actor_id, &format!("{} removed {}", name_of(&names, &actor_id), name_of(&names, &user_id))).await?;
+        tx.commit().await?;
+        Ok::<_, AuthzError>(Some(message))
+    }
+    .await;
+    match result {
+        Ok(Some(message)) => notify(pool.get_ref(), &srv, conversation_id, "participant_removed", json!({"actor_id": actor_id, "user_id": user_id}), vec![user_id], vec![message]).await,
+        Ok(None) => HttpResponse::NotFound().json(json!({"message": "User is not a participant of this group"})),
+        Err(AuthzError::Database(e)) => { log::error!("Failed to remove participant from {}: {}", conversation_id, e); HttpResponse::InternalServerError().finish() }
+        Err(e) => e.error_response(),
+    }
+}
+
+enum AdminChange { Changed(Box<ChatMessage>), Unchanged, NotParticipant, LastAdmin }
+
+pub async fn set_admin(pool: web::Data<PgPool>, srv: web::Data<Addr<ChatServer>>, req: HttpRequest, path: web::Path<(Uuid, Uuid)>, body: web::Json<SetAdminRequest>) -> impl Responder {
+    let actor_id = Uuid::parse_str(&req.extensions().get::<Claims>().unwrap().sub).unwrap();
+    let (conversation_id, user_id) = path.into_inner();
+    let is_admin = body.is_admin;
+
+    let result = async {
+        let mut tx = pool.begin().await?;
+        lock_as_admin(&mut tx, conversation_id, actor_id).await?;
+        let current = sqlx::query_scalar!("SELECT is_admin FROM conversation_participants WHERE conversation_id = $1 AND user_id = $2", conversation_id, user_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+        match current {
+            None => return Ok(AdminChange::NotParticipant),
+            Some(current) if current == is_admin => return Ok(AdminChange::Unchanged),
+            Some(_) => {}
+        }
+        sqlx::query!("UPDATE conversation_participants SET is_admin = $3 WHERE conversation_id = $1 AND user_id = $2", conversation_id, user_id, is_admin)
+            .execute(&mut *tx)
+            .await?;
+        let admins = sqlx::query_scalar!(r#"SELECT COUNT(*) as "count!" FROM conversation_participants WHERE conversation_id = $1 AND is_admin"#, conversation_id)
+            .fetch_one(&mut *tx)
+            .await?;
+        if admins == 0 {
+            return Ok(AdminChange::LastAdmin);
+        }
+        let names = display_names(&mut tx, &[actor_id, user_id]).await?;
+        let content = if is_admin {
+            format!("{} made {} an admin", name_of(&names, &actor_id), name_of(&names, &user_id))
+        } else if user_id == actor_id {
+            format!("{} is no longer an admin", name_of(&names, &actor_id))
+        } else {
+            format!("{} removed {} as admin", name_of(&names, &actor_id), name_of(&names, &user_id))
+        };
+        let message = insert_system_message(&mut tx, conversation_id, actor_id, &content).await?;
+        tx.commit().await?;
+        Ok::<_, AuthzError>(AdminChange::Changed(Box::new(message)))
+    }
+    .await;
+    match result {
+        Ok(AdminChange::Changed(message)) => notify(pool.get_ref(), &srv, conversation_id, "admin_changed", json!({"actor_id": actor_id, "user_id": user_id, "is_admin": is_admin}), Vec::new(), vec![*message]).await,
+        Ok(AdminChange::Unchanged) => respond_with_conversation(pool.get_ref(), conversation_id).await,
+        Ok(AdminChange::NotParticipant) => HttpResponse::NotFound().json(json!({"message": "User is not a participant of this group"})),
+        Ok(AdminChange::LastAdmin) => HttpResponse::Conflict().json(json!({"message": "A group must keep at least one admin"})),
+        Err(AuthzError::Database(e)) => { log::error!("Failed to change admin in {}: {}", conversation_id, e); HttpResponse::InternalServerError().finish() }
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn update_group(pool: web::Data<PgPool>, srv: web::Data<Addr<ChatServer>>, req: HttpRequest, path: web::Path<Uuid>, body: web::Json<UpdateGroupRequest>) -> impl Responder {
+    let actor_id = Uuid::parse_str(&req.extensions().get::<Claims>().unwrap().sub).unwrap();
+    let conversation_id = path.into_inner();
+    let UpdateGroupRequest { group_name, group_icon_url } = body.into_inner();
+    let group_name = group_name.map(|name| name.trim().to_owned());
+    if group_name.as_deref() == Some("") {
+        return HttpResponse::BadRequest().json(json!({"message": "group_name cannot be empty"}));
+    }
+    if group_name.is_none() && group_icon_url.is_none() {
+        return HttpResponse::BadRequest().json(json!({"message": "Nothing to update"}));
+    }
+
+    let result = async {
+        let mut tx = pool.begin().await?;
+        lock_as_admin(&mut tx, conversation_id, actor_id).await?;
+        let updated = sqlx::query!(
+            "UPDATE conversations SET group_name = COALESCE($2, group_name), group_icon_url = CASE WHEN $3::text IS NULL THEN group_icon_url ELSE NULLIF($3, '') END
+             WHERE id = $1 RETURNING group_name, group_icon_url",
+            conversation_id,
+            group_name,
+            group_icon_url
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        let names = display_names(&mut tx, &[actor_id]).await?;
+        let actor = name_of(&names, &actor_id);
+        let mut messages = Vec::new();
+        if let Some(name) = &group_name {
+            messages.push(insert_system_message(&mut tx, conversation_id, actor_id, &format!("{} changed the group name to \"{}\"", actor, name)).await?);
+        }
+        if group_icon_url.is_some() {
+            let action = if updated.group_icon_url.is_some() { "changed the group icon" } else { "removed the group icon" };
+            messages.push(insert_system_message(&mut tx, conversation_id, actor_id, &format!("{} {}", actor, action)).await?);
+        }
+        tx.commit().await?;
+        Ok::<_, AuthzError>((updated, messages))
+    }
+    .await;
+    match result {
+        Ok((updated, messages)) => {
+            let data = json!({"actor_id": actor_id, "group_name": updated.group_name, "group_icon_url": updated.group_icon_url});
+            notify(pool.get_ref(), &srv, conversation_id, "group_updated", data, Vec::new(), messages).await
+        }
+        Err(AuthzError::Database(e)) => { log::error!("Failed to update group {}: {}", conversation_id, e); HttpResponse::InternalServerError().finish() }
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn leave_group(pool: web::Data<PgPool>, srv: web::Data<Addr<ChatServer>>, req: HttpRequest, path: web::Path<Uuid>) -> impl Responder {
+    let user_id = Uuid::parse_str(&req.extensions().get::<Claims>().unwrap().sub).unwrap();
+    let conversation_id = path.into_inner();
+
+    let result = async {
+        let mut tx = pool.begin().await?;
+        let was_admin = lock_group(&mut tx, conversation_id, user_id).await?;
+        sqlx::query!("DELETE FROM conversation_participants WHERE conversation_id = $1 AND user_id = $2", conversation_id, user_id).execute(&mut *tx).await?;
+        record_departure(&mut tx, conversation_id, user_id).await?;
+        // The last admin leaving hands the group over to the longest-standing member
+        let promoted = if was_admin {
+            sqlx::query_scalar!(
+                "UPDATE conversation_participants SET is_admin = TRUE
+                 WHERE conversation_id = $1 AND user_id = (SELECT user_id FROM conversation_participants WHERE conversation_id = $1 ORDER BY joined_at, user_id LIMIT 1)
+                 AND NOT EXISTS (SELECT 1 FROM conversation_participants WHERE conversation_id = $1 AND is_admin)
+                 RETURNING user_id",
+                conversation_id
+            )
+            .fetch_optional(&mut *tx)
+            .await?
+        } else {
+            None
+        };
+        let names = display_names(&mut tx, &[&[user_id][..], promoted.as_slice()].concat()).await?;
+        let mut messages = vec![insert_system_message(&mut tx, conversation_id, user_id, &format!("{} left", name_of(&names, &user_id))).await?];
+        if let Some(promoted) = promoted {
+            messages.push(insert_system_message(&mut tx, conversation_id, user_id, &format!("{} is now an admin", name_of(&names, &promoted))).await?);
+        }
+        tx.commit().await?;
+        Ok::<_, AuthzError>((promoted, messages))
+    }
+    .await;
+    match result {
+        Ok((promoted, messages)) => {
+            notify(pool.get_ref(), &srv, conversation_id, "participant_left", json!({"user_id": user_id, "promoted_admin_id": promoted}), vec![user_id], messages).await;
+            HttpResponse::NoContent().finish()
+        }
+        Err(AuthzError::Database(e)) => { log::error!("Failed to leave group {}: {}", conversation_id, e); HttpResponse::InternalServerError().finish() }
+        Err(e) => e.error_response(),
+    }
+}
+
+/// Locks the group row for the rest of the transaction, so changes to one group's membership and
+/// admins happen one at a time, then returns `user_id`'s role as it stands under that lock.
+async fn lock_group(conn: &mut PgConnection, conversation_id: Uuid, user_id: Uuid) -> Result<bool, AuthzError> {
+    sqlx::query!("SELECT id FROM conversations WHERE id = $1 FOR UPDATE", conversation_id).fetch_optional(&mut *conn).await?;
+    group_role(conn, conversation_id, user_id).await
+}
+
+/// Like `lock_group`, but only admins may go on.
+async fn lock_as_admin(conn: &mut PgConnection, conversation_id: Uuid, user_id: Uuid) -> Result<(), AuthzError> {
+    if lock_group(conn, conversation_id, user_id).await? { Ok(()) } else { Err(AuthzError::NotAdmin) }
+}
+
+/// The name to show for `user_id` in a system message, even if the user was deleted meanwhile.
+fn name_of<'a>(names: &'a HashMap<Uuid, String>, user_id: &Uuid) -> &'a str {
+    names.get(user_id).map_or("Someone", String::as_str)
+}
+
+/// Falls back to the phone number for users who have not set a name yet.
+async fn display_names(conn: &mut PgConnection, user_ids: &[Uuid]) -> sqlx::Result<HashMap<Uuid, String>> {
+    let rows = sqlx::query!(r#"SELECT id, COALESCE(name, phone_number) as "display_name!" FROM users WHERE id = ANY($1)"#, user_ids).fetch_all(conn).await?;
+    Ok(rows.into_iter().map(|row| (row.id, row.display_name)).collect())
+}
+
+/// Remembers when a user stopped being a member so offline sync can still replay the change to them.
+async fn record_departure(conn: &mut PgConnection, conversation_id: Uuid, user_id: Uuid) -> sqlx::Result<()> {
+    sqlx::query!("INSERT INTO conversation_departures (conversation_id, user_id) VALUES ($1, $2)", conversation_id, user_id).execute(conn).await?;
+    Ok(())
+}
+
+async fn insert_system_message(conn: &mut PgConnection, conversation_id: Uuid, sender_id: Uuid, content: &str) -> sqlx::Result<ChatMessage> {
+    sqlx::query_as!(
+        ChatMessage,
+        r#"INSERT INTO messages (conversation_id, sender_id, message_type, content) VALUES ($1, $2, 'system', $3)
+           RETURNING id, conversation_id, sender_id, message_type as "message_type: MessageType", content, status as "status: MessageStatus", media as "media: Json<MediaAttachment>", created_at"#,
+        conversation_id,
+        sender_id,
+        content
+    )
+    .fetch_one(conn)
+    .await
+}
+
+async fn respond_with_conversation(pool: &PgPool, conversation_id: Uuid) -> HttpResponse {
+    match fetch_conversation(pool, conversation_id).await {
+        Ok(conversation) => HttpResponse::Ok().json(conversation),
+        Err(e) => { log::error!("Failed to load conversation {}: {}", conversation_id, e); HttpResponse::InternalServerError().finish() }
+    }
+}
+
+/// Pushes `event` and the system messages to everyone affected and answers with the updated group.
+async fn notify(pool: &PgPool, srv: &Addr<ChatServer>, conversation_id: Uuid, event: &str, mut data: Value, removed: Vec<Uuid>, messages: Vec<ChatMessage>) -> HttpResponse {
+    let conversation = match fetch_conversation(pool, conversation_id).await {
+        Ok(conversation) => conversation,
+        Err(e) => { log::error!("Failed to load conversation {}: {}", conversation_id, e); return HttpResponse::InternalServerError().finish(); }
+    };
+    data["conversation_id"] = json!(conversation_id);
+    data["conversation"] = json!(conversation);
+    let mut events = vec![json!({"event": event, "data": data}).to_string()];
+    events.extend(messages.iter().map(|message| json!({"event": "new_message", "data": message}).to_string()));
+    let members = conversation.participants.iter().map(|p| p.user_id).collect();
+    srv.do_send(GroupChanged { conversation_id, members, removed, events });
+    HttpResponse::Ok().json(conversation)
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/conversations/{id}").route(web::patch().to(update_group)))
+       .service(web::resource("/conversations/{id}/leave").route(web::post().to(leave_group)))
+       .service(web::resource("/conversations/{id}/participants").route(web::post().to(add_participants)))
+       .service(web::resource("/conversations/{id}/participants/{user_id}").route(web::delete().to(remove_participant)))
+       .service(web::resource("/conversations/{id}/participants/{user_id}/admin").route(web::put().to(set_admin)));
+}
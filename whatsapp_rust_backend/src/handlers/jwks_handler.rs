@@ -0,0 +1,12 @@
+use crate::utils::jwt::keys;
+use actix_web::{web, HttpResponse, Responder};
+
+/// Public keys that verify access tokens, for services that check them without holding a secret.
+/// Verifiers may cache the set for a few minutes; a rotated-in key is published before it signs.
+pub async fn get_jwks() -> impl Responder {
+    HttpResponse::Ok().insert_header(("Cache-Control", "public, max-age=300")).json(keys().jwks())
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/.well-known/jwks.json").route(web::get().to(get_jwks)));
+}
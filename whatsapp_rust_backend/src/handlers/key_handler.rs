@@ -1 +1,290 @@
-use actix_web::web; pub fn config(_cfg: &mut web::ServiceConfig) {}
+use crate::models::{Claims, KeyBundle, OneTimePreKey, ReplenishPreKeysRequest, SignedPreKey, UploadKeyBundleRequest};
+use actix_web::{http::StatusCode, web, HttpMessage, HttpRequest, HttpResponse, Responder, ResponseError};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{Duration, Utc};
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde_json::json;
+use sqlx::{types::{Json, Uuid}, PgPool};
+use std::{collections::HashSet, fmt};
+
+/// One-time prekeys accepted in a single upload, and kept per user at most.
+const MAX_PRE_KEYS_PER_UPLOAD: usize = 100;
+const MAX_STORED_PRE_KEYS: usize = 200;
+
+/// One-time prekeys a requester may take within `PRE_KEY_CLAIM_WINDOW`, from any one user and in
+/// total. Past either limit bundles come without one, so nobody can drain another user's prekeys.
+const MAX_PRE_KEY_CLAIMS_PER_USER: i64 = 5;
+const MAX_PRE_KEY_CLAIMS: i64 = 100;
+pub const PRE_KEY_CLAIM_WINDOW: Duration = Duration::hours(1);
+
+/// Why a key upload or fetch was refused. Every variant has a stable `code` for clients.
+#[derive(Debug)]
+enum KeyError { InvalidKey(&'static str), InvalidSignature, TooManyPreKeys, DuplicatePreKeyIds, NoBundle, NotFound, Database(sqlx::Error) }
+
+impl KeyError {
+    fn code(&self) -> &'static str {
+        match self {
+            KeyError::InvalidKey(_) => "invalid_key",
+            KeyError::InvalidSignature => "invalid_signature",
+            KeyError::TooManyPreKeys => "too_many_pre_keys",
+            KeyError::DuplicatePreKeyIds => "duplicate_pre_key_ids",
+            KeyError::NoBundle => "no_key_bundle",
+            KeyError::NotFound => "key_bundle_not_found",
+            KeyError::Database(_) => "internal_error",
+        }
+    }
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::InvalidKey(field) => write!(f, "'{}' must be the raw key bytes in standard base64: 32 bytes for keys (no 0x05 type prefix), 64 for signatures", field),
+            KeyError::InvalidSignature => write!(f, "The signed prekey's signature does not verify against the identity key"),
+            KeyError::TooManyPreKeys => write!(f, "Upload at most {} one-time prekeys at once, and keep at most {}", MAX_PRE_KEYS_PER_UPLOAD, MAX_STORED_PRE_KEYS),
+            KeyError::DuplicatePreKeyIds => write!(f, "One-time prekey ids must be unique, including among the keys already uploaded"),
+            KeyError::NoBundle => write!(f, "Upload a key bundle before adding one-time prekeys"),
+            KeyError::NotFound => write!(f, "This user has not uploaded any keys"),
+            KeyError::Database(_) => write!(f, "Something went wrong; try again"),
+        }
+    }
+}
+
+impl ResponseError for KeyError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            KeyError::NoBundle | KeyError::NotFound => StatusCode::NOT_FOUND,
+            KeyError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+    fn error_response(&self) -> HttpResponse {
+        if let KeyError::Database(e) = self { log::error!("Key bundle query failed: {}", e); }
+        HttpResponse::build(self.status_code()).json(json!({"code": self.code(), "message": self.to_string()}))
+    }
+}
+
+impl From<sqlx::Error> for KeyError {
+    fn from(e: sqlx::Error) -> Self { KeyError::Database(e) }
+}
+
+/// Decodes a base64 value of exactly `N` bytes.
+fn decode<const N: usize>(field: &'static str, value: &str) -> Result<[u8; N], KeyError> {
+    STANDARD.decode(value).ok().and_then(|bytes| bytes.try_into().ok()).ok_or(KeyError::InvalidKey(field))
+}
+
+/// The identity key is an Ed25519 public key, and the signed prekey is only accepted with an Ed25519
+/// signature by it over the prekey's 32 raw bytes, so a client fetching the bundle can trust it came
+/// from that identity. This is not Signal's XEdDSA: clients sign with the Ed25519 identity key itself.
+fn verify_signed_pre_key(identity_key: &str, signed_pre_key: &SignedPreKey) -> Result<(), KeyError> {
+    let identity_key = VerifyingKey::from_bytes(&decode("identity_key", identity_key)?).map_err(|_| KeyError::InvalidKey("identity_key"))?;
+    let public_key = decode::<32>("signed_pre_key.public_key", &signed_pre_key.public_key)?;
+    let signature = Signature::from_bytes(&decode("signed_pre_key.signature", &signed_pre_key.signature)?);
+    identity_key.verify_strict(&public_key, &signature).map_err(|_| KeyError::InvalidSignature)
+}
+
+/// Checks the one-time prekeys of an upload: well-formed, few enough, and with ids not in `existing`.
+fn check_pre_keys(pre_keys: &[OneTimePreKey], existing: &[OneTimePreKey]) -> Result<(), KeyError> {
+    if pre_keys.len() > MAX_PRE_KEYS_PER_UPLOAD || existing.len() + pre_keys.len() > MAX_STORED_PRE_KEYS {
+        return Err(KeyError::TooManyPreKeys);
+    }
+    let mut ids: HashSet<i32> = existing.iter().map(|k| k.key_id).collect();
+    for pre_key in pre_keys {
+        decode::<32>("one_time_pre_keys.public_key", &pre_key.public_key)?;
+        if !ids.insert(pre_key.key_id) { return Err(KeyError::DuplicatePreKeyIds); }
+    }
+    Ok(())
+}
+
+/// Publishes the caller's identity key, signed prekey and one-time prekeys, replacing whatever was
+/// uploaded before (including any one-time prekeys not yet handed out).
+async fn upload_bundle(pool: &PgPool, user_id: Uuid, bundle: UploadKeyBundleRequest) -> Result<usize, KeyError> {
+    verify_signed_pre_key(&bundle.identity_key, &bundle.signed_pre_key)?;
+    check_pre_keys(&bundle.one_time_pre_keys, &[])?;
+    sqlx::query!(
+        r#"INSERT INTO user_key_bundles (user_id, identity_key, signed_pre_key, signed_pre_key_id, signed_pre_key_signature, one_time_pre_keys) VALUES ($1, $2, $3, $4, $5, $6)
+           ON CONFLICT (user_id) DO UPDATE SET identity_key = EXCLUDED.identity_key, signed_pre_key = EXCLUDED.signed_pre_key, signed_pre_key_id = EXCLUDED.signed_pre_key_id,
+           signed_pre_key_signature = EXCLUDED.signed_pre_key_signature, one_time_pre_keys = EXCLUDED.one_time_pre_keys, updated_at = NOW()"#,
+        user_id,
+        bundle.identity_key,
+        bundle.signed_pre_key.public_key,
+        bundle.signed_pre_key.key_id,
+        bundle.signed_pre_key.signature,
+        Json(&bundle.one_time_pre_keys) as _
+    )
+    .execute(pool)
+    .await?;
+    Ok(bundle.one_time_pre_keys.len())
+}
+
+/// Adds one-time prekeys to the caller's bundle and returns how many are now available. Bundles
+/// from before signed prekeys were verified count as missing: they have to be uploaded again.
+async fn replenish(pool: &PgPool, user_id: Uuid, pre_keys: Vec<OneTimePreKey>) -> Result<usize, KeyError> {
+    let mut tx = pool.begin().await?;
+    let existing = sqlx::query_scalar!(r#"SELECT one_time_pre_keys as "keys: Json<Vec<OneTimePreKey>>" FROM user_key_bundles WHERE user_id = $1 AND signed_pre_key_signature IS NOT NULL FOR UPDATE"#, user_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(KeyError::NoBundle)?;
+    check_pre_keys(&pre_keys, &existing)?;
+    sqlx::query!("UPDATE user_key_bundles SET one_time_pre_keys = one_time_pre_keys || $2, updated_at = NOW() WHERE user_id = $1", user_id, Json(&pre_keys) as _)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(existing.len() + pre_keys.len())
+}
+
+/// `user_id`'s bundle with one of their one-time prekeys, which is removed so that no one else is
+/// handed it. The row stays locked until the key is gone, so concurrent fetches get different keys.
+/// A requester over its claim limits gets the bundle without a one-time prekey.
+async fn claim_bundle(pool: &PgPool, requester_id: Uuid, user_id: Uuid) -> Result<KeyBundle, KeyError> {
+    let mut tx = pool.begin().await?;
+    let bundle = sqlx::query!(
+        r#"SELECT identity_key, signed_pre_key, signed_pre_key_id as "signed_pre_key_id!", signed_pre_key_signature as "signed_pre_key_signature!", one_time_pre_keys->0 as "one_time_pre_key: Json<OneTimePreKey>"
+           FROM user_key_bundles WHERE user_id = $1 AND signed_pre_key_signature IS NOT NULL FOR UPDATE"#,
+        user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(KeyError::NotFound)?;
+    let mut one_time_pre_key = bundle.one_time_pre_key.map(|k| k.0);
+    if one_time_pre_key.is_some() {
+        // The row lock above only covers `user_id`; this one makes the requester's fetches from
+        // different users count their claims one at a time, so together they cannot pass the total
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtextextended($1, 0))").bind(format!("pre_key_claims:{}", requester_id)).execute(&mut *tx).await?;
+        let claims = sqlx::query!(
+            r#"SELECT COUNT(*) FILTER (WHERE user_id = $2) as "from_user!", COUNT(*) as "total!" FROM pre_key_claims WHERE requester_id = $1 AND claimed_at > $3"#,
+            requester_id,
+            user_id,
+            Utc::now() - PRE_KEY_CLAIM_WINDOW
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if claims.from_user < MAX_PRE_KEY_CLAIMS_PER_USER && claims.total < MAX_PRE_KEY_CLAIMS {
+            sqlx::query!("UPDATE user_key_bundles SET one_time_pre_keys = one_time_pre_keys - 0 WHERE user_id = $1", user_id).execute(&mut *tx).await?;
+            sqlx::query!("INSERT INTO pre_key_claims (requester_id, user_id) VALUES ($1, $2)", requester_id, user_id).execute(&mut *tx).await?;
+        } else {
+            log::warn!("User {} reached the prekey claim limit fetching {}'s bundle", requester_id, user_id);
+            one_time_pre_key = None;
+        }
+    }
+    tx.commit().await?;
+    Ok(KeyBundle {
+        user_id,
+        identity_key: bundle.identity_key,
+        signed_pre_key: SignedPreKey { key_id: bundle.signed_pre_key_id, public_key: bundle.signed_pre_key, signature: bundle.signed_pre_key_signature },
+        one_time_pre_key,
+    })
+}
+
+/// Uploads or replaces the caller's key bundle. See `SignedPreKey` for the key formats and what is signed.
+pub async fn put_bundle(pool: web::Data<PgPool>, req: HttpRequest, body: web::Json<UploadKeyBundleRequest>) -> impl Responder {
+    let user_id = Uuid::parse_str(&req.extensions().get::<Claims>().unwrap().sub).unwrap();
+    match upload_bundle(pool.get_ref(), user_id, body.into_inner()).await {
+        Ok(count) => HttpResponse::Ok().json(json!({ "one_time_pre_keys": count })),
+        Err(e) => e.error_response(),
+    }
+}
+/// Tops up the caller's one-time prekeys.
+pub async fn post_one_time_pre_keys(pool: web::Data<PgPool>, req: HttpRequest, body: web::Json<ReplenishPreKeysRequest>) -> impl Responder {
+    let user_id = Uuid::parse_str(&req.extensions().get::<Claims>().unwrap().sub).unwrap();
+    match replenish(pool.get_ref(), user_id, body.into_inner().one_time_pre_keys).await {
+        Ok(count) => HttpResponse::Ok().json(json!({ "one_time_pre_keys": count })),
+        Err(e) => e.error_response(),
+    }
+}
+/// How many one-time prekeys the caller has left, so the client knows when to upload more.
+/// `needs_bundle` is set while the caller has no bundle others can fetch, e.g. one uploaded without a signature.
+pub async fn get_one_time_pre_key_count(pool: web::Data<PgPool>, req: HttpRequest) -> impl Responder {
+    let user_id = Uuid::parse_str(&req.extensions().get::<Claims>().unwrap().sub).unwrap();
+    let count = sqlx::query_scalar!(r#"SELECT jsonb_array_length(one_time_pre_keys) as "count!" FROM user_key_bundles WHERE user_id = $1 AND signed_pre_key_signature IS NOT NULL"#, user_id)
+        .fetch_optional(pool.get_ref())
+        .await;
+    match count {
+        Ok(count) => HttpResponse::Ok().json(json!({ "one_time_pre_keys": count.unwrap_or(0), "needs_bundle": count.is_none() })),
+        Err(e) => KeyError::Database(e).error_response(),
+    }
+}
+/// Fetches another user's bundle to start an encrypted session with them, consuming one of their
+/// one-time prekeys. `one_time_pre_key` is null once they have run out, or once the caller has taken
+/// as many as `MAX_PRE_KEY_CLAIMS_PER_USER` allows.
+pub async fn get_bundle(pool: web::Data<PgPool>, req: HttpRequest, path: web::Path<Uuid>) -> impl Responder {
+    let requester_id = Uuid::parse_str(&req.extensions().get::<Claims>().unwrap().sub).unwrap();
+    match claim_bundle(pool.get_ref(), requester_id, path.into_inner()).await {
+        Ok(bundle) => HttpResponse::Ok().json(bundle),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/keys/bundle").route(web::put().to(put_bundle)))
+       .service(web::resource("/keys/one-time").route(web::post().to(post_one_time_pre_keys)))
+       .service(web::resource("/keys/one-time/count").route(web::get().to(get_one_time_pre_key_count)))
+       .service(web::resource("/keys/{user_id}/bundle").route(web::get().to(get_bundle)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn pre_key(key_id: i32) -> OneTimePreKey { OneTimePreKey { key_id, public_key: STANDARD.encode([key_id as u8; 32]) } }
+
+    fn pre_keys(ids: std::ops::Range<i32>) -> Vec<OneTimePreKey> { ids.map(pre_key).collect() }
+
+    /// An identity key and a signed prekey signed by it.
+    fn signed_bundle() -> (String, SignedPreKey) {
+        let identity = SigningKey::from_bytes(&[7; 32]);
+        let public_key = [9; 32];
+        let signed_pre_key = SignedPreKey { key_id: 1, public_key: STANDARD.encode(public_key), signature: STANDARD.encode(identity.sign(&public_key).to_bytes()) };
+        (STANDARD.encode(identity.verifying_key().as_bytes()), signed_pre_key)
+    }
+
+    #[test]
+    fn empty_batch_is_accepted() {
+        assert!(check_pre_keys(&[], &[]).is_ok());
+        assert!(check_pre_keys(&[], &pre_keys(0..MAX_STORED_PRE_KEYS as i32)).is_ok());
+    }
+
+    #[test]
+    fn duplicate_ids_are_refused() {
+        assert!(matches!(check_pre_keys(&[pre_key(1), pre_key(2), pre_key(1)], &[]), Err(KeyError::DuplicatePreKeyIds)));
+        assert!(matches!(check_pre_keys(&[pre_key(5)], &pre_keys(0..10)), Err(KeyError::DuplicatePreKeyIds)));
+        assert!(check_pre_keys(&[pre_key(10)], &pre_keys(0..10)).is_ok());
+    }
+
+    #[test]
+    fn keys_must_be_32_bytes_of_base64() {
+        for public_key in [STANDARD.encode([1; 31]), STANDARD.encode([1; 33]), "not base64!".to_owned(), String::new()] {
+            let batch = [OneTimePreKey { key_id: 1, public_key }];
+            assert!(matches!(check_pre_keys(&batch, &[]), Err(KeyError::InvalidKey(_))), "accepted {:?}", batch[0].public_key);
+        }
+    }
+
+    #[test]
+    fn batch_and_stored_limits() {
+        assert!(check_pre_keys(&pre_keys(0..MAX_PRE_KEYS_PER_UPLOAD as i32), &[]).is_ok());
+        assert!(matches!(check_pre_keys(&pre_keys(0..MAX_PRE_KEYS_PER_UPLOAD as i32 + 1), &[]), Err(KeyError::TooManyPreKeys)));
+        let stored = pre_keys(0..MAX_STORED_PRE_KEYS as i32 - 1);
+        let next = MAX_STORED_PRE_KEYS as i32;
+        assert!(check_pre_keys(&pre_keys(next..next + 1), &stored).is_ok());
+        assert!(matches!(check_pre_keys(&pre_keys(next..next + 2), &stored), Err(KeyError::TooManyPreKeys)));
+    }
+
+    #[test]
+    fn signed_pre_key_must_be_signed_by_the_identity_key() {
+        let (identity_key, signed_pre_key) = signed_bundle();
+        assert!(verify_signed_pre_key(&identity_key, &signed_pre_key).is_ok());
+
+        let other_key = SignedPreKey { public_key: STANDARD.encode([8; 32]), ..signed_pre_key.clone() };
+        assert!(matches!(verify_signed_pre_key(&identity_key, &other_key), Err(KeyError::InvalidSignature)));
+
+        let other_identity = STANDARD.encode(SigningKey::from_bytes(&[6; 32]).verifying_key().as_bytes());
+        assert!(matches!(verify_signed_pre_key(&other_identity, &signed_pre_key), Err(KeyError::InvalidSignature)));
+
+        // Signal-style keys carry a 0x05 type byte and are refused as malformed rather than misread
+        let prefixed = SignedPreKey { public_key: STANDARD.encode([[5].as_slice(), &[9; 32]].concat()), ..signed_pre_key.clone() };
+        assert!(matches!(verify_signed_pre_key(&identity_key, &prefixed), Err(KeyError::InvalidKey(_))));
+
+        let truncated = SignedPreKey { signature: STANDARD.encode([0; 63]), ..signed_pre_key };
+        assert!(matches!(verify_signed_pre_key(&identity_key, &truncated), Err(KeyError::InvalidKey(_))));
+    }
+}
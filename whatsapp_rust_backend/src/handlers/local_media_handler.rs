@@ -0,0 +1,46 @@
+use crate::storage::{local::LocalStore, MediaStore};
+use crate::utils::media_policy::mime_type_for_key;
+use actix_web::{http::header, web, HttpRequest, HttpResponse, Responder};
+use serde::Deserialize;
+use serde_json::json;
+
+/// Query string of a URL signed by `LocalStore`; the signature stands in for a JWT.
+#[derive(Deserialize)]
+pub struct SignedUrlQuery { expires: i64, signature: String }
+
+fn invalid_signature() -> HttpResponse {
+    HttpResponse::Forbidden().json(json!({"code": "invalid_signature", "message": "The URL is invalid or has expired"}))
+}
+
+pub async fn put_object(store: web::Data<LocalStore>, req: HttpRequest, path: web::Path<String>, query: web::Query<SignedUrlQuery>, payload: web::Payload) -> impl Responder {
+    let key = path.into_inner();
+    let content_type = req.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or_default();
+    let Some(content_length) = req.headers().get(header::CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<i64>().ok()) else {
+        return HttpResponse::LengthRequired().finish();
+    };
+    if !store.verify("PUT", &key, query.expires, content_type, Some(content_length), &query.signature) {
+        return invalid_signature();
+    }
+    match store.write(&key, payload, content_length).await {
+        Ok(Some(etag)) => HttpResponse::Ok().insert_header((header::ETAG, format!("\"{}\"", etag))).finish(),
+        Ok(None) => HttpResponse::BadRequest().json(json!({"code": "size_mismatch", "message": "The body does not match the signed Content-Length"})),
+        Err(e) => { log::error!("Failed to store {}: {}", key, e); HttpResponse::InternalServerError().finish() }
+    }
+}
+
+pub async fn get_object(store: web::Data<LocalStore>, path: web::Path<String>, query: web::Query<SignedUrlQuery>) -> impl Responder {
+    let key = path.into_inner();
+    if !store.verify("GET", &key, query.expires, "", None, &query.signature) {
+        return invalid_signature();
+    }
+    match store.get(&key).await {
+        Ok(Some(bytes)) => HttpResponse::Ok().content_type(mime_type_for_key(&key).unwrap_or("application/octet-stream")).body(bytes),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => { log::error!("Failed to read {}: {}", key, e); HttpResponse::InternalServerError().finish() }
+    }
+}
+
+/// Only registered when `MEDIA_STORE=local`.
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/media/local/{key:.+}").route(web::put().to(put_object)).route(web::get().to(get_object)));
+}
@@ -1,13 +1,134 @@
-use actix_web::{web, HttpResponse, Responder};
-use aws_sdk_s3::{presigning::PresigningConfig, Client};
+use crate::actors::thumbnailer::{GenerateThumbnails, Thumbnailer};
+use crate::models::{Claims, MediaKind, MediaUpload, MediaUploadState, UploadUrlRequest};
+use crate::storage::MediaStore;
+use crate::utils::authz::require_media_access;
+use crate::utils::media_policy::MediaPolicy;
+use actix::Addr;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder, ResponseError};
+use serde_json::json;
+use sqlx::PgPool;
 use std::time::Duration;
-pub async fn get_upload_url(s3_client: web::Data<Client>) -> impl Responder {
-    let bucket_name = std::env::var("S3_BUCKET_NAME").expect("S3_BUCKET_NAME must be set");
-    let object_key = format!("uploads/{}.jpg", uuid::Uuid::new_v4());
-    match 
-s3_client.put_object().bucket(bucket_name).key(object_key.clone()).presigned(PresigningConfig::expires_in(Duration::from_secs(300)).unwrap()).await {
-        Ok(p) => HttpResponse::Ok().json(serde_json::json!({"url": p.uri().to_string(), "key": object_key})),
-        Err(e) => { log::error!("S3 presign failed: {:?}", e); HttpResponse::InternalServerError().finish() }
+use uuid::Uuid;
+/// Every upload lives under its owner's prefix, which is how media messages prove ownership.
+pub(crate) fn user_upload_prefix(user_id: Uuid) -> String { format!("uploads/{}/", user_id) }
+
+const UPLOAD_URL_TTL: Duration = Duration::from_secs(300);
+
+/// Presigns a PUT for a file the client declared up front. The declared `Content-Type` and
+/// `Content-Length` are part of the signature, so the client must send exactly those headers.
+pub async fn get_upload_url(store: web::Data<dyn MediaStore>, pool: web::Data<PgPool>, policy: web::Data<MediaPolicy>, req: HttpRequest, body: web::Json<UploadUrlRequest>) -> impl Responder {
+    let user_id = Uuid::parse_str(&req.extensions().get::<Claims>().unwrap().sub).unwrap();
+    let body = body.into_inner();
+    let mime_type = body.mime_type.trim().to_ascii_lowercase();
+    let accepted = match policy.check(&mime_type, body.size_bytes) {
+        Ok(accepted) => accepted,
+        Err(v) => return HttpResponse::BadRequest().json(json!({"code": v.code(), "message": v.message()})),
+    };
+    let object_key = format!("{}{}.{}", user_upload_prefix(user_id), Uuid::new_v4(), accepted.extension);
+    let presigned = match store.presign_upload(&object_key, &mime_type, body.size_bytes, UPLOAD_URL_TTL).await {
+        Ok(p) => p,
+        Err(e) => { log::error!("Presigning upload failed: {:?}", e); return HttpResponse::InternalServerError().finish(); }
+    };
+    let upload_id = match sqlx::query_scalar!(
+        "INSERT INTO media_uploads (owner_id, object_key, kind, mime_type, size_bytes) VALUES ($1, $2, $3, $4, $5) RETURNING id",
+        user_id,
+        object_key,
+        accepted.kind as MediaKind,
+        mime_type,
+        body.size_bytes
+    )
+    .fetch_one(pool.get_ref())
+    .await
+    {
+        Ok(id) => id,
+        Err(e) => { log::error!("Failed to record upload: {}", e); return HttpResponse::InternalServerError().finish(); }
+    };
+    HttpResponse::Ok().json(json!({
+        "id": upload_id,
+        "method": presigned.method,
+        "url": presigned.url,
+        "key": object_key,
+        "kind": accepted.kind,
+        "headers": presigned.headers,
+        "expires_in": UPLOAD_URL_TTL.as_secs(),
+    }))
+}
+
+pub(crate) async fn load_upload(pool: &PgPool, upload_id: Uuid, owner_id: Uuid) -> sqlx::Result<Option<MediaUpload>> {
+    sqlx::query_as!(
+        MediaUpload,
+        r#"SELECT id, owner_id, object_key, kind as "kind: MediaKind", mime_type, size_bytes, state as "state: MediaUploadState", created_at
+           FROM media_uploads WHERE id = $1 AND owner_id = $2"#,
+        upload_id,
+        owner_id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Called by the client once its PUT succeeded. The stored object must match the declared upload;
+/// if it does not, it is deleted and the upload cannot be used. Images then get their previews generated.
+pub async fn complete_upload(store: web::Data<dyn MediaStore>, pool: web::Data<PgPool>, thumbnailer: web::Data<Addr<Thumbnailer>>, req: HttpRequest, path: web::Path<Uuid>) -> impl Responder {
+    let user_id = Uuid::parse_str(&req.extensions().get::<Claims>().unwrap().sub).unwrap();
+    let upload_id = path.into_inner();
+    let upload = match load_upload(pool.get_ref(), upload_id, user_id).await {
+        Ok(Some(upload)) => upload,
+        Ok(None) => return HttpResponse::NotFound().json(json!({"message": "Upload not found"})),
+        Err(e) => { log::error!("Failed to load upload {}: {}", upload_id, e); return HttpResponse::InternalServerError().finish(); }
+    };
+    match upload.state {
+        MediaUploadState::Uploaded | MediaUploadState::Attached => return HttpResponse::Ok().json(upload),
+        MediaUploadState::Deleted => return HttpResponse::Gone().json(json!({"code": "upload_deleted", "message": "This upload was discarded"})),
+        MediaUploadState::Pending => {}
+    }
+    finish_upload(store.get_ref(), pool.get_ref(), thumbnailer.get_ref(), upload).await
+}
+
+/// Checks the stored object of a pending upload against what was declared and marks it uploaded,
+/// or deletes it on a mismatch. Shared by single PUT and multipart uploads.
+pub(crate) async fn finish_upload(store: &dyn MediaStore, pool: &PgPool, thumbnailer: &Addr<Thumbnailer>, upload: MediaUpload) -> HttpResponse {
+    let stored = match store.head(&upload.object_key).await {
+        Ok(Some(meta)) => meta,
+        Ok(None) => return HttpResponse::Conflict().json(json!({"code": "upload_missing", "message": "Nothing has been uploaded yet"})),
+        Err(e) => { log::error!("Failed to inspect {}: {:?}", upload.object_key, e); return HttpResponse::InternalServerError().finish(); }
+    };
+    let matches = stored.size_bytes == upload.size_bytes && stored.content_type.as_deref().is_none_or(|ct| ct == upload.mime_type);
+    let state = if matches { MediaUploadState::Uploaded } else { MediaUploadState::Deleted };
+    if state == MediaUploadState::Deleted {
+        if let Err(e) = store.delete(&upload.object_key).await {
+            log::error!("Failed to delete mismatched upload {}: {:?}", upload.object_key, e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+    if let Err(e) = sqlx::query!("UPDATE media_uploads SET state = $2, updated_at = NOW() WHERE id = $1", upload.id, state as MediaUploadState).execute(pool).await {
+        log::error!("Failed to update upload {}: {}", upload.id, e);
+        return HttpResponse::InternalServerError().finish();
+    }
+    if state == MediaUploadState::Deleted {
+        return HttpResponse::Conflict().json(json!({"code": "size_mismatch", "message": "The stored object does not match the declared type and size"}));
     }
+    if upload.kind == MediaKind::Image {
+        thumbnailer.do_send(GenerateThumbnails { upload_id: upload.id });
+    }
+    HttpResponse::Ok().json(MediaUpload { state, ..upload })
+}
+
+const DOWNLOAD_URL_TTL: Duration = Duration::from_secs(300);
+
+pub async fn get_download_url(store: web::Data<dyn MediaStore>, pool: web::Data<PgPool>, req: HttpRequest, path: web::Path<String>) -> impl Responder {
+    let user_id = Uuid::parse_str(&req.extensions().get::<Claims>().unwrap().sub).unwrap();
+    let object_key = path.into_inner();
+    if let Err(e) = require_media_access(pool.get_ref(), &object_key, user_id).await {
+        return e.error_response();
+    }
+    match store.presign_download(&object_key, DOWNLOAD_URL_TTL).await {
+        Ok(p) => HttpResponse::Ok().json(json!({"url": p.url, "key": object_key, "expires_in": DOWNLOAD_URL_TTL.as_secs()})),
+        Err(e) => { log::error!("Presigning download failed: {:?}", e); HttpResponse::InternalServerError().finish() }
+    }
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/media/upload-url").route(web::post().to(get_upload_url)))
+       .service(web::resource("/media/uploads/{id}/complete").route(web::post().to(complete_upload)))
+       .service(web::resource("/media/{key:.+}/download-url").route(web::get().to(get_download_url)));
 }
-pub fn config(cfg: &mut web::ServiceConfig) { cfg.service(web::resource("/media/upload-url").route(web::post().to(get_upload_url))); }
@@ -1,2 +1,2 @@
-pub mod auth_handler; pub mod conversation_handler; pub mod key_handler; pub mod media_handler; pub mod qr_auth_handler; pub mod user_handler; pub mod 
+pub mod auth_handler; pub mod conversation_handler; pub mod device_handler; pub mod group_handler; pub mod jwks_handler; pub mod key_handler; pub mod local_media_handler; pub mod media_handler; pub mod multipart_upload_handler; pub mod otp_inbox_handler; pub mod qr_auth_handler; pub mod user_handler; pub mod 
 ws_handler;
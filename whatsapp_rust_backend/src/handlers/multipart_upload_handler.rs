@@ -0,0 +1,282 @@
+use crate::actors::thumbnailer::Thumbnailer;
+use crate::handlers::media_handler::{finish_upload, load_upload, user_upload_prefix};
+use crate::models::{Claims, MediaKind, MediaUploadState, RecordPartRequest, UploadUrlRequest};
+use crate::storage::{MediaStore, UploadedPart};
+use crate::utils::media_policy::MediaPolicy;
+use actix::Addr;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use serde_json::json;
+use sqlx::PgPool;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// S3 requires every part but the last to be at least 5 MiB and allows at most 10,000 parts.
+const PART_SIZE: i64 = 8 * 1024 * 1024;
+const MAX_PARTS: i64 = 10_000;
+const PART_URL_TTL: Duration = Duration::from_secs(900);
+
+fn part_count(size_bytes: i64, part_size: i64) -> i64 { (size_bytes + part_size - 1) / part_size }
+
+/// Every part is `part_size` bytes except the last, which holds the remainder.
+fn part_length(size_bytes: i64, part_size: i64, part_number: i32) -> i64 { part_size.min(size_bytes - (part_number as i64 - 1) * part_size) }
+
+/// A multipart upload that is still accepting parts.
+struct PendingMultipart { id: Uuid, object_key: String, size_bytes: i64, multipart_id: String, part_size: i64 }
+
+impl PendingMultipart {
+    fn part_count(&self) -> i64 { part_count(self.size_bytes, self.part_size) }
+}
+
+async fn load_pending(pool: &PgPool, upload_id: Uuid, owner_id: Uuid) -> Result<PendingMultipart, HttpResponse> {
+    let row = sqlx::query!(
+        r#"SELECT id, object_key, size_bytes, state as "state: MediaUploadState", multipart_id, part_size FROM media_uploads WHERE id = $1 AND owner_id = $2"#,
+        upload_id,
+        owner_id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| { log::error!("Failed to load upload {}: {}", upload_id, e); HttpResponse::InternalServerError().finish() })?;
+    match row {
+        Some(row) if row.state == MediaUploadState::Pending => match (row.multipart_id, row.part_size) {
+            (Some(multipart_id), Some(part_size)) => Ok(PendingMultipart { id: row.id, object_key: row.object_key, size_bytes: row.size_bytes, multipart_id, part_size }),
+            _ => Err(HttpResponse::BadRequest().json(json!({"code": "not_multipart", "message": "This upload does not use parts"}))),
+        },
+        Some(_) => Err(HttpResponse::Conflict().json(json!({"code": "upload_closed", "message": "This upload is no longer accepting parts"}))),
+        None => Err(HttpResponse::NotFound().json(json!({"message": "Upload not found"}))),
+    }
+}
+
+/// Starts a resumable upload. The client then uploads `part_count` parts of `part_size` bytes
+/// (the last one shorter) in any order, reporting each one, and completes the upload.
+pub async fn start_multipart(store: web::Data<dyn MediaStore>, pool: web::Data<PgPool>, policy: web::Data<MediaPolicy>, req: HttpRequest, body: web::Json<UploadUrlRequest>) -> impl Responder {
+    let user_id = Uuid::parse_str(&req.extensions().get::<Claims>().unwrap().sub).unwrap();
+    let body = body.into_inner();
+    let mime_type = body.mime_type.trim().to_ascii_lowercase();
+    let accepted = match policy.check(&mime_type, body.size_bytes) {
+        Ok(accepted) => accepted,
+        Err(v) => return HttpResponse::BadRequest().json(json!({"code": v.code(), "message": v.message()})),
+    };
+    if part_count(body.size_bytes, PART_SIZE) > MAX_PARTS {
+        return HttpResponse::BadRequest().json(json!({"code": "upload_too_large", "message": "The upload has too many parts"}));
+    }
+    let object_key = format!("{}{}.{}", user_upload_prefix(user_id), Uuid::new_v4(), accepted.extension);
+    let multipart_id = match store.start_multipart(&object_key, &mime_type).await {
+        Ok(id) => id,
+        Err(e) => { log::error!("Starting multipart upload failed: {:?}", e); return HttpResponse::InternalServerError().finish(); }
+    };
+    let inserted = sqlx::query_scalar!(
+        "INSERT INTO media_uploads (owner_id, object_key, kind, mime_type, size_bytes, multipart_id, part_size) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id",
+        user_id,
+        object_key,
+        accepted.kind as MediaKind,
+        mime_type,
+        body.size_bytes,
+        multipart_id,
+        PART_SIZE
+    )
+    .fetch_one(pool.get_ref())
+    .await;
+    match inserted {
+        Ok(upload_id) => HttpResponse::Created().json(json!({
+            "id": upload_id, "key": object_key, "kind": accepted.kind, "part_size": PART_SIZE, "part_count": part_count(body.size_bytes, PART_SIZE),
+        })),
+        Err(e) => {
+            log::error!("Failed to record upload: {}", e);
+            if let Err(e) = store.abort_multipart(&object_key, &multipart_id).await {
+                log::error!("Failed to abort multipart upload {}: {:?}", object_key, e);
+            }
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Lists the parts reported so far, so an interrupted client knows where to resume.
+pub async fn get_multipart(pool: web::Data<PgPool>, req: HttpRequest, path: web::Path<Uuid>) -> impl Responder {
+    let user_id = Uuid::parse_str(&req.extensions().get::<Claims>().unwrap().sub).unwrap();
+    let upload = match load_pending(pool.get_ref(), path.into_inner(), user_id).await {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+    match sqlx::query_scalar!("SELECT part_number FROM media_upload_parts WHERE upload_id = $1 ORDER BY part_number", upload.id).fetch_all(pool.get_ref()).await {
+        Ok(uploaded) => HttpResponse::Ok().json(json!({
+            "id": upload.id, "key": upload.object_key, "part_size": upload.part_size, "part_count": upload.part_count(), "uploaded_parts": uploaded,
+        })),
+        Err(e) => { log::error!("Failed to load parts of {}: {}", upload.id, e); HttpResponse::InternalServerError().finish() }
+    }
+}
+
+pub async fn get_part_url(store: web::Data<dyn MediaStore>, pool: web::Data<PgPool>, req: HttpRequest, path: web::Path<(Uuid, i32)>) -> impl Responder {
+    let user_id = Uuid::parse_str(&req.extensions().get::<Claims>().unwrap().sub).unwrap();
+    let (upload_id, part_number) = path.into_inner();
+    let upload = match load_pending(pool.get_ref(), upload_id, user_id).await {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+    if part_number < 1 || part_number as i64 > upload.part_count() {
+        return HttpResponse::BadRequest().json(json!({"code": "invalid_part", "message": format!("Part numbers run from 1 to {}", upload.part_count())}));
+    }
+    let length = part_length(upload.size_bytes, upload.part_size, part_number);
+    match store.presign_part(&upload.object_key, &upload.multipart_id, part_number, length, PART_URL_TTL).await {
+        Ok(p) => HttpResponse::Ok().json(json!({"part_number": part_number, "method": p.method, "url": p.url, "headers": p.headers, "expires_in": PART_URL_TTL.as_secs()})),
+        Err(e) => { log::error!("Presigning part {} of {} failed: {:?}", part_number, upload.id, e); HttpResponse::InternalServerError().finish() }
+    }
+}
+
+/// Records a part the client finished uploading. Reporting a part again replaces its ETag.
+pub async fn record_part(pool: web::Data<PgPool>, req: HttpRequest, path: web::Path<(Uuid, i32)>, body: web::Json<RecordPartRequest>) -> impl Responder {
+    let user_id = Uuid::parse_str(&req.extensions().get::<Claims>().unwrap().sub).unwrap();
+    let (upload_id, part_number) = path.into_inner();
+    let upload = match load_pending(pool.get_ref(), upload_id, user_id).await {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+    if part_number < 1 || part_number as i64 > upload.part_count() || body.etag.trim().is_empty() {
+        return HttpResponse::BadRequest().json(json!({"code": "invalid_part", "message": "Unknown part number or empty ETag"}));
+    }
+    // Touching the upload keeps an active upload away from the abandoned-upload cleanup
+    let recorded = sqlx::query!(
+        "WITH part AS (
+            INSERT INTO media_upload_parts (upload_id, part_number, etag) VALUES ($1, $2, $3)
+            ON CONFLICT (upload_id, part_number) DO UPDATE SET etag = EXCLUDED.etag, created_at = NOW()
+         )
+         UPDATE media_uploads SET updated_at = NOW() WHERE id = $1",
+        upload.id,
+        part_number,
+        body.etag.trim()
+    )
+    .execute(pool.get_ref())
+    .await;
+    match recorded {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => { log::error!("Failed to record part {} of {}: {}", part_number, upload.id, e); HttpResponse::InternalServerError().finish() }
+    }
+}
+
+/// Assembles the reported parts and then verifies the result like a single PUT upload.
+pub async fn complete_multipart(store: web::Data<dyn MediaStore>, pool: web::Data<PgPool>, thumbnailer: web::Data<Addr<Thumbnailer>>, req: HttpRequest, path: web::Path<Uuid>) -> impl Responder {
+    let user_id = Uuid::parse_str(&req.extensions().get::<Claims>().unwrap().sub).unwrap();
+    let upload = match load_pending(pool.get_ref(), path.into_inner(), user_id).await {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+    let parts = match sqlx::query!("SELECT part_number, etag FROM media_upload_parts WHERE upload_id = $1 ORDER BY part_number", upload.id).fetch_all(pool.get_ref()).await {
+        Ok(rows) => rows.into_iter().map(|r| UploadedPart { part_number: r.part_number, etag: r.etag }).collect::<Vec<_>>(),
+        Err(e) => { log::error!("Failed to load parts of {}: {}", upload.id, e); return HttpResponse::InternalServerError().finish(); }
+    };
+    let missing: Vec<i64> = (1..=upload.part_count()).filter(|n| !parts.iter().any(|p| p.part_number as i64 == *n)).collect();
+    if !missing.is_empty() {
+        return HttpResponse::Conflict().json(json!({"code": "parts_missing", "message": "Some parts have not been uploaded", "missing_parts": missing}));
+    }
+    if let Err(e) = store.complete_multipart(&upload.object_key, &upload.multipart_id, &parts).await {
+        log::warn!("Assembling {} failed: {:?}", upload.object_key, e);
+        return HttpResponse::Conflict().json(json!({"code": "parts_invalid", "message": "The uploaded parts could not be assembled; re-upload them and try again"}));
+    }
+    if let Err(e) = sqlx::query!("DELETE FROM media_upload_parts WHERE upload_id = $1", upload.id).execute(pool.get_ref()).await {
+        log::error!("Failed to clear parts of {}: {}", upload.id, e);
+    }
+    match load_upload(pool.get_ref(), upload.id, user_id).await {
+        Ok(Some(row)) => finish_upload(store.get_ref(), pool.get_ref(), thumbnailer.get_ref(), row).await,
+        Ok(None) => HttpResponse::NotFound().json(json!({"message": "Upload not found"})),
+        Err(e) => { log::error!("Failed to load upload {}: {}", upload.id, e); HttpResponse::InternalServerError().finish() }
+    }
+}
+
+pub async fn abort_multipart(store: web::Data<dyn MediaStore>, pool: web::Data<PgPool>, req: HttpRequest, path: web::Path<Uuid>) -> impl Responder {
+    let user_id = Uuid::parse_str(&req.extensions().get::<Claims>().unwrap().sub).unwrap();
+    let upload = match load_pending(pool.get_ref(), path.into_inner(), user_id).await {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+    if let Err(e) = store.abort_multipart(&upload.object_key, &upload.multipart_id).await {
+        log::error!("Failed to abort multipart upload {}: {:?}", upload.object_key, e);
+        return HttpResponse::InternalServerError().finish();
+    }
+    match sqlx::query!("UPDATE media_uploads SET state = 'deleted', updated_at = NOW() WHERE id = $1", upload.id).execute(pool.get_ref()).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => { log::error!("Failed to mark upload {} deleted: {}", upload.id, e); HttpResponse::InternalServerError().finish() }
+    }
+}
+
+/// Aborts multipart uploads that have not received a part for `ttl_hours`, freeing their parts
+/// in the store, and returns how many were aborted. Uploads the store fails to abort are retried
+/// on the next run.
+pub async fn abort_abandoned_multiparts(pool: PgPool, store: web::Data<dyn MediaStore>, ttl_hours: i32) -> sqlx::Result<u64> {
+    let abandoned = sqlx::query!(
+        r#"SELECT id, object_key, multipart_id as "multipart_id!" FROM media_uploads
+           WHERE state = 'pending' AND multipart_id IS NOT NULL AND updated_at < NOW() - make_interval(hours => $1)"#,
+        ttl_hours
+    )
+    .fetch_all(&pool)
+    .await?;
+    let mut aborted = 0;
+    for upload in &abandoned {
+        if let Err(e) = store.abort_multipart(&upload.object_key, &upload.multipart_id).await {
+            log::error!("Failed to abort multipart upload {}: {:?}", upload.object_key, e);
+            continue;
+        }
+        // The parts go with the upload row's state; `media_upload_parts` rows are only useful while pending
+        sqlx::query!(
+            "WITH cleared AS (DELETE FROM media_upload_parts WHERE upload_id = $1) UPDATE media_uploads SET state = 'deleted', updated_at = NOW() WHERE id = $1",
+            upload.id
+        )
+        .execute(&pool)
+        .await?;
+        aborted += 1;
+    }
+    Ok(aborted)
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/media/multipart").route(web::post().to(start_multipart)))
+       .service(web::resource("/media/multipart/{id}").route(web::get().to(get_multipart)).route(web::delete().to(abort_multipart)))
+       .service(web::resource("/media/multipart/{id}/parts/{part_number}").route(web::put().to(record_part)))
+       .service(web::resource("/media/multipart/{id}/parts/{part_number}/url").route(web::get().to(get_part_url)))
+       .service(web::resource("/media/multipart/{id}/complete").route(web::post().to(complete_multipart)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The parts of a file always add up to exactly its size.
+    fn assert_parts_cover(size_bytes: i64, part_size: i64) {
+        let total: i64 = (1..=part_count(size_bytes, part_size) as i32).map(|n| part_length(size_bytes, part_size, n)).sum();
+        assert_eq!(total, size_bytes);
+    }
+
+    #[test]
+    fn exact_multiple_of_the_part_size() {
+        assert_eq!(part_count(3 * PART_SIZE, PART_SIZE), 3);
+        assert_eq!(part_length(3 * PART_SIZE, PART_SIZE, 3), PART_SIZE);
+        assert_parts_cover(3 * PART_SIZE, PART_SIZE);
+    }
+
+    #[test]
+    fn short_last_part() {
+        let size = 2 * PART_SIZE + 1;
+        assert_eq!(part_count(size, PART_SIZE), 3);
+        assert_eq!(part_length(size, PART_SIZE, 2), PART_SIZE);
+        assert_eq!(part_length(size, PART_SIZE, 3), 1);
+        assert_parts_cover(size, PART_SIZE);
+        assert_eq!(part_count(3 * PART_SIZE - 1, PART_SIZE), 3);
+        assert_eq!(part_length(3 * PART_SIZE - 1, PART_SIZE, 3), PART_SIZE - 1);
+    }
+
+    #[test]
+    fn single_part_file() {
+        for size in [1, PART_SIZE / 2, PART_SIZE] {
+            assert_eq!(part_count(size, PART_SIZE), 1);
+            assert_eq!(part_length(size, PART_SIZE, 1), size);
+        }
+    }
+
+    #[test]
+    fn maximum_part_count() {
+        let largest = MAX_PARTS * PART_SIZE;
+        assert_eq!(part_count(largest, PART_SIZE), MAX_PARTS);
+        assert_eq!(part_length(largest, PART_SIZE, MAX_PARTS as i32), PART_SIZE);
+        assert_parts_cover(largest, PART_SIZE);
+        // One byte more needs a part beyond the limit
+        assert_eq!(part_count(largest + 1, PART_SIZE), MAX_PARTS + 1);
+    }
+}
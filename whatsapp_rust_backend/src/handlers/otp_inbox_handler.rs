@@ -0,0 +1,15 @@
+use crate::{otp::dev::InMemoryOtpSender, utils::phone::normalize_phone};
+use actix_web::{web, HttpResponse, Responder, ResponseError};
+
+/// Codes "sent" to a phone number by the in-memory OTP sender, for tests and local development.
+pub async fn get_inbox(inbox: web::Data<InMemoryOtpSender>, path: web::Path<String>) -> impl Responder {
+    match normalize_phone(&path.into_inner()) {
+        Ok(phone_number) => HttpResponse::Ok().json(inbox.messages_for(&phone_number)),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// Only registered when `OTP_SENDER=memory`.
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/dev/otp-inbox/{phone_number}").route(web::get().to(get_inbox)));
+}
@@ -1,48 +1,200 @@
-use crate::{models::Claims, utils::jwt::create_jwt};
-use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use crate::{actors::{qr_login::{QrConfirmed, QrLoginServer, QrLoginSession, QrScanned}, server::ChatServer}, models::{AuthResponse, Claims, DevicePlatform, NewQrSessionQuery}, utils::refresh_tokens::{sign_in, NewDevice}};
+use actix::Addr;
+use actix_web::{http::StatusCode, web, Error, HttpMessage, HttpRequest, HttpResponse, Responder, ResponseError};
+use actix_web_actors::ws;
+use chrono::{DateTime, Duration, Utc};
+use serde_json::json;
 use sqlx::{types::Uuid, PgPool};
+use std::{env, fmt};
 
+/// `pending` until a phone scans the code, `scanned` until that phone confirms, `authenticated`
+/// until the browser collects its tokens, then `consumed`.
 #[derive(Debug, sqlx::Type, PartialEq)]
 #[sqlx(type_name = "qr_session_status", rename_all = "lowercase")]
-enum QrSessionStatus { Pending, Scanned, Authenticated, }
+enum QrSessionStatus { Pending, Scanned, Authenticated, Consumed }
 
-pub async fn new_qr_session(pool: web::Data<PgPool>) -> impl Responder {
-    match sqlx::query!("INSERT INTO qr_sessions (status) VALUES ('pending') RETURNING session_id").fetch_one(pool.get_ref()).await {
-        Ok(r) => HttpResponse::Ok().json(serde_json::json!({ "session_id": r.session_id.to_string() })),
+/// How long a QR code can be used, from `QR_SESSION_TTL_SECONDS` (120 by default). The whole
+/// scan and confirm flow has to finish within it.
+pub fn qr_session_ttl() -> Duration {
+    Duration::seconds(env::var("QR_SESSION_TTL_SECONDS").ok().and_then(|v| v.parse().ok()).unwrap_or(120))
+}
+
+/// Sessions created before this have expired.
+fn expiry_cutoff() -> DateTime<Utc> { Utc::now() - qr_session_ttl() }
+
+/// Why a QR session cannot move on. Every variant has a stable `code` for clients.
+#[derive(Debug)]
+enum QrError { NotFound, Expired, Consumed, AlreadyScanned, NotScanned, Database(sqlx::Error) }
+
+impl QrError {
+    fn code(&self) -> &'static str {
+        match self {
+            QrError::NotFound => "qr_session_not_found",
+            QrError::Expired => "qr_session_expired",
+            QrError::Consumed => "qr_session_consumed",
+            QrError::AlreadyScanned => "qr_session_already_scanned",
+            QrError::NotScanned => "qr_session_not_scanned",
+            QrError::Database(_) => "internal_error",
+        }
+    }
+}
+
+impl fmt::Display for QrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QrError::NotFound => write!(f, "This QR code does not exist"),
+            QrError::Expired => write!(f, "This QR code has expired; reload it and scan again"),
+            QrError::Consumed => write!(f, "This QR code was already used to sign in"),
+            QrError::AlreadyScanned => write!(f, "This QR code was already scanned"),
+            QrError::NotScanned => write!(f, "Scan this QR code before confirming it"),
+            QrError::Database(_) => write!(f, "Something went wrong; try again"),
+        }
+    }
+}
+
+impl ResponseError for QrError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            QrError::NotFound => StatusCode::NOT_FOUND,
+            QrError::Expired | QrError::Consumed => StatusCode::GONE,
+            QrError::AlreadyScanned | QrError::NotScanned => StatusCode::CONFLICT,
+            QrError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+    fn error_response(&self) -> HttpResponse {
+        if let QrError::Database(e) = self { log::error!("QR session query failed: {}", e); }
+        let mut body = json!({"code": self.code(), "message": self.to_string()});
+        // Terminal states are reported like the others, so pollers can stop on `status`
+        match self {
+            QrError::Expired => body["status"] = json!("expired"),
+            QrError::Consumed => body["status"] = json!("consumed"),
+            _ => {}
+        }
+        HttpResponse::build(self.status_code()).json(body)
+    }
+}
+
+impl From<sqlx::Error> for QrError {
+    fn from(e: sqlx::Error) -> Self { QrError::Database(e) }
+}
+
+/// Explains why a session did not make the expected transition. Live sessions come with their expiry.
+async fn current_state(pool: &PgPool, session_id: Uuid) -> Result<(QrSessionStatus, DateTime<Utc>), QrError> {
+    let session = sqlx::query!(r#"SELECT status as "status: QrSessionStatus", created_at FROM qr_sessions WHERE session_id = $1"#, session_id)
+        .fetch_optional(pool).await?.ok_or(QrError::NotFound)?;
+    match session.status {
+        QrSessionStatus::Consumed => Err(QrError::Consumed),
+        _ if session.created_at <= expiry_cutoff() => Err(QrError::Expired),
+        status => Ok((status, session.created_at + qr_session_ttl())),
+    }
+}
+
+/// Claims a confirmed session and signs its browser in as a new device. The claim is atomic, so
+/// whichever of a poll or the watching socket gets there first receives the tokens, exactly once.
+pub(crate) async fn release_qr_session(pool: &PgPool, chat_server: &Addr<ChatServer>, session_id: Uuid) -> anyhow::Result<Option<AuthResponse>> {
+    let released = sqlx::query!(
+        r#"UPDATE qr_sessions SET status = 'consumed' WHERE session_id = $1 AND status = 'authenticated' AND created_at > $2
+           RETURNING user_id as "user_id!", COALESCE(device_name, 'Unknown device') as "device_name!", device_platform as "device_platform: DevicePlatform""#,
+        session_id,
+        expiry_cutoff()
+    )
+    .fetch_optional(pool).await?;
+    match released {
+        Some(r) => Ok(Some(sign_in(pool, chat_server, r.user_id, NewDevice { name: r.device_name, platform: r.device_platform }).await?)),
+        None => Ok(None),
+    }
+}
+
+/// The browser asks for a code to display. It describes itself now, since it is the device being linked.
+pub async fn new_qr_session(pool: web::Data<PgPool>, req: HttpRequest, query: web::Query<NewQrSessionQuery>) -> impl Responder {
+    let device = NewDevice::describe(query.device_name.as_deref(), query.platform.or(Some(DevicePlatform::Web)), &req);
+    let created = sqlx::query!(
+        "INSERT INTO qr_sessions (status, device_name, device_platform) VALUES ('pending', $1, $2) RETURNING session_id",
+        device.name,
+        device.platform as Option<DevicePlatform>
+    )
+    .fetch_one(pool.get_ref()).await;
+    match created {
+        Ok(r) => HttpResponse::Ok().json(json!({ "session_id": r.session_id.to_string(), "expires_in": qr_session_ttl().num_seconds() })),
         Err(e) => { log::error!("New QR session failed: {}", e); HttpResponse::InternalServerError().finish() }
     }
 }
-pub async fn scan_qr_session(pool: web::Data<PgPool>, req: HttpRequest, path: web::Path<Uuid>) -> impl Responder {
+/// The phone claims the session. Nothing is released until it also confirms.
+pub async fn scan_qr_session(pool: web::Data<PgPool>, qr_login: web::Data<Addr<QrLoginServer>>, req: HttpRequest, path: web::Path<Uuid>) -> impl Responder {
+    let user_id = Uuid::parse_str(&req.extensions().get::<Claims>().unwrap().sub).unwrap();
+    let session_id = path.into_inner();
+    let scanned = sqlx::query!("UPDATE qr_sessions SET status = 'scanned', user_id = $1 WHERE session_id = $2 AND status = 'pending' AND created_at > $3", user_id, session_id, expiry_cutoff())
+        .execute(pool.get_ref()).await;
+    match scanned {
+        Ok(r) if r.rows_affected() > 0 => { qr_login.do_send(QrScanned { session_id }); HttpResponse::Ok().json(json!({ "status": "scanned" })) }
+        Ok(_) => match current_state(pool.get_ref(), session_id).await {
+            Ok(_) => QrError::AlreadyScanned.error_response(),
+            Err(e) => e.error_response(),
+        },
+        Err(e) => QrError::Database(e).error_response(),
+    }
+}
+/// The phone that scanned the session approves signing the browser in.
+pub async fn confirm_qr_session(pool: web::Data<PgPool>, qr_login: web::Data<Addr<QrLoginServer>>, req: HttpRequest, path: web::Path<Uuid>) -> impl Responder {
     let user_id = Uuid::parse_str(&req.extensions().get::<Claims>().unwrap().sub).unwrap();
-    let new_jwt = create_jwt(&user_id.to_string()).unwrap();
-    match sqlx::query!("UPDATE qr_sessions SET status = 'scanned', user_id = $1, jwt = $2 WHERE session_id = $3", user_id, new_jwt, 
-path.into_inner()).execute(pool.get_ref()).await {
-        Ok(r) if r.rows_affected() > 0 => HttpResponse::Ok().finish(),
-        Ok(_) => HttpResponse::NotFound().finish(),
-        Err(e) => { log::error!("Scan QR failed: {}", e); HttpResponse::InternalServerError().finish() }
-    }
-}
-pub async fn poll_qr_session(pool: web::Data<PgPool>, path: web::Path<Uuid>) -> impl Responder {
-    let query_res = sqlx::query!(r#"SELECT status as "status: QrSessionStatus", jwt FROM qr_sessions WHERE session_id = $1"#, 
-path.into_inner()).fetch_optional(pool.get_ref()).await;
-    match query_res {
-        Ok(Some(record)) => {
-            // THE DEFINITIVE FIX: record.status is NOT an Option. We match on its variants directly.
-            match record.status {
-                QrSessionStatus::Scanned | QrSessionStatus::Authenticated => {
-                    HttpResponse::Ok().json(serde_json::json!({ "token": record.jwt }))
-                }
-                QrSessionStatus::Pending => {
-                    HttpResponse::Ok().json(serde_json::json!({ "status": "pending" }))
-                }
-            }
+    let session_id = path.into_inner();
+    let confirmed = sqlx::query!("UPDATE qr_sessions SET status = 'authenticated' WHERE session_id = $1 AND status = 'scanned' AND user_id = $2 AND created_at > $3", session_id, user_id, expiry_cutoff())
+        .execute(pool.get_ref()).await;
+    match confirmed {
+        Ok(r) if r.rows_affected() > 0 => { qr_login.do_send(QrConfirmed { session_id }); HttpResponse::Ok().json(json!({ "status": "authenticated" })) }
+        // Pending, or scanned (or already confirmed) by another phone
+        Ok(_) => match current_state(pool.get_ref(), session_id).await {
+            Ok(_) => QrError::NotScanned.error_response(),
+            Err(e) => e.error_response(),
+        },
+        Err(e) => QrError::Database(e).error_response(),
+    }
+}
+/// Reports the session's `status`. Once confirmed, the first poll claims the session and receives
+/// tokens for a new device; every later poll sees `consumed`.
+pub async fn poll_qr_session(pool: web::Data<PgPool>, chat_server: web::Data<Addr<ChatServer>>, path: web::Path<Uuid>) -> impl Responder {
+    let session_id = path.into_inner();
+    match release_qr_session(pool.get_ref(), &chat_server, session_id).await {
+        Ok(Some(auth)) => {
+            let mut body = json!(auth);
+            body["status"] = json!("authenticated");
+            HttpResponse::Ok().json(body)
         }
-        Ok(None) => HttpResponse::NotFound().finish(),
-        Err(e) => { log::error!("Poll QR failed: {}", e); HttpResponse::InternalServerError().finish() }
+        Ok(None) => match current_state(pool.get_ref(), session_id).await {
+            Ok((QrSessionStatus::Pending, _)) => HttpResponse::Ok().json(json!({ "status": "pending" })),
+            Ok((QrSessionStatus::Scanned, _)) => HttpResponse::Ok().json(json!({ "status": "scanned" })),
+            // Confirmed, but too late to be released
+            Ok((QrSessionStatus::Authenticated | QrSessionStatus::Consumed, _)) => QrError::Expired.error_response(),
+            Err(e) => e.error_response(),
+        },
+        Err(e) => { log::error!("Failed to release QR session {}: {:?}", session_id, e); HttpResponse::InternalServerError().finish() }
     }
 }
+/// Instead of polling, the browser can open this socket for its session. It is told `pending` or
+/// `scanned` on connect, `scanned` when the phone scans, and `authenticated` with its tokens when
+/// the phone confirms, after which the socket closes; it closes with `expired` when the code expires.
+pub async fn watch_qr_session(pool: web::Data<PgPool>, qr_login: web::Data<Addr<QrLoginServer>>, req: HttpRequest, stream: web::Payload, path: web::Path<Uuid>) -> Result<HttpResponse, Error> {
+    let session_id = path.into_inner();
+    let (status, expires_at) = match current_state(pool.get_ref(), session_id).await {
+        Ok(state) => state,
+        Err(e) => return Ok(e.error_response()),
+    };
+    let status = match status {
+        QrSessionStatus::Pending => "pending",
+        QrSessionStatus::Scanned => "scanned",
+        QrSessionStatus::Authenticated | QrSessionStatus::Consumed => "authenticated",
+    };
+    let expires_in = (expires_at - Utc::now()).to_std().unwrap_or_default();
+    ws::start(QrLoginSession::new(session_id, status, expires_in, qr_login.get_ref().clone()), &req, stream)
+}
+/// Routes for the browser showing the code; they are public.
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.service(web::resource("/auth/qr/new").route(web::get().to(new_qr_session)))
-       .service(web::resource("/auth/qr/scan/{session_id}").route(web::post().to(scan_qr_session)))
-       .service(web::resource("/auth/qr/poll/{session_id}").route(web::get().to(poll_qr_session)));
+       .service(web::resource("/auth/qr/poll/{session_id}").route(web::get().to(poll_qr_session)))
+       .service(web::resource("/auth/qr/ws/{session_id}").route(web::get().to(watch_qr_session)));
+}
+/// Routes for the signed-in phone; they need `JwtAuth`.
+pub fn phone_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/auth/qr/scan/{session_id}").route(web::post().to(scan_qr_session)))
+       .service(web::resource("/auth/qr/confirm/{session_id}").route(web::post().to(confirm_qr_session)));
 }
@@ -1 +1,39 @@
-use actix_web::web; pub fn config(_cfg: &mut web::ServiceConfig) {}
+use crate::models::{ContactLookupRequest, ContactMatch, User};
+use crate::utils::phone::normalize_phone;
+use actix_web::{web, HttpResponse, Responder};
+use serde_json::json;
+use sqlx::PgPool;
+use std::collections::HashMap;
+
+/// Upper bound on numbers per lookup; larger address books are sent in batches.
+const MAX_LOOKUP_NUMBERS: usize = 1000;
+
+/// Contact discovery: which numbers in the caller's address book belong to registered users.
+/// Numbers are matched in their E.164 form, so they can be sent however the address book formats
+/// them; those that do not parse are returned under `invalid`.
+pub async fn lookup_contacts(pool: web::Data<PgPool>, req: web::Json<ContactLookupRequest>) -> impl Responder {
+    if req.phone_numbers.len() > MAX_LOOKUP_NUMBERS {
+        return HttpResponse::BadRequest().json(json!({"code": "too_many_numbers", "message": format!("At most {} numbers can be looked up at once", MAX_LOOKUP_NUMBERS)}));
+    }
+    let (mut normalized, mut invalid) = (Vec::new(), Vec::new());
+    for phone_number in &req.phone_numbers {
+        match normalize_phone(phone_number) {
+            Ok(e164) => normalized.push((phone_number, e164)),
+            Err(_) => invalid.push(phone_number),
+        }
+    }
+    let e164s: Vec<String> = normalized.iter().map(|(_, e164)| e164.clone()).collect();
+    let users = match sqlx::query_as!(User, "SELECT id, phone_number, name FROM users WHERE phone_number = ANY($1)", &e164s).fetch_all(pool.get_ref()).await {
+        Ok(users) => users.into_iter().map(|u| (u.phone_number.clone(), u)).collect::<HashMap<_, _>>(),
+        Err(e) => { log::error!("Failed to look up contacts: {}", e); return HttpResponse::InternalServerError().finish(); }
+    };
+    let matches: Vec<ContactMatch> = normalized
+        .into_iter()
+        .filter_map(|(phone_number, e164)| users.get(&e164).map(|user| ContactMatch { phone_number: phone_number.clone(), user: user.clone() }))
+        .collect();
+    HttpResponse::Ok().json(json!({"matches": matches, "invalid": invalid}))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/users/lookup").route(web::post().to(lookup_contacts)));
+}
@@ -1,12 +1,16 @@
-use crate::{actors::session::WebSocketSession, utils::jwt::decode_jwt, actors::server::ChatServer};
+use crate::{actors::session::WebSocketSession, utils::{jwt::decode_jwt, refresh_tokens::touch_device}, actors::server::ChatServer};
 use actix::Addr;
 use actix_web::{web, Error, HttpRequest, HttpResponse};
 use actix_web_actors::ws;
+use sqlx::PgPool;
 use uuid::Uuid;
-pub async fn ws_connect(req: HttpRequest, stream: web::Payload, srv: web::Data<Addr<ChatServer>>) -> Result<HttpResponse, Error> {
+pub async fn ws_connect(req: HttpRequest, stream: web::Payload, srv: web::Data<Addr<ChatServer>>, pool: web::Data<PgPool>) -> Result<HttpResponse, Error> {
     let token = req.query_string().split('&').find(|s| s.starts_with("token=")).map(|s| s.split('=').nth(1).unwrap_or("")).unwrap_or("");
-    match decode_jwt(token) {
-        Ok(claims) => ws::start(WebSocketSession::new(Uuid::parse_str(&claims.sub).unwrap(), srv.get_ref().clone()),&req,stream),
-        Err(_) => Ok(HttpResponse::Unauthorized().finish()),
+    let Ok(claims) = decode_jwt(token) else { return Ok(HttpResponse::Unauthorized().finish()) };
+    let user_id = Uuid::parse_str(&claims.sub).unwrap();
+    match touch_device(pool.get_ref(), user_id, claims.device_id).await {
+        Ok(true) => ws::start(WebSocketSession::new(user_id, claims.device_id, srv.get_ref().clone()),&req,stream),
+        Ok(false) => Ok(HttpResponse::Unauthorized().finish()),
+        Err(e) => { log::error!("Device check failed: {}", e); Ok(HttpResponse::InternalServerError().finish()) }
     }
 }
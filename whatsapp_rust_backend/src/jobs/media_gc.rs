@@ -0,0 +1,89 @@
+use crate::models::{MediaUploadState, Thumbnail};
+use crate::storage::MediaStore;
+use actix::{Actor, AsyncContext, Context, WrapFuture};
+use actix_web::web;
+use sqlx::{types::Json, PgPool};
+use std::{env, time::Duration};
+
+/// Deletes media nobody can reach any more:
+/// - uploads that were never attached to a message within `MEDIA_GC_GRACE_HOURS` (24 by default),
+/// - attached uploads whose messages are gone, e.g. because the conversation was deleted.
+///
+/// Runs every `MEDIA_GC_INTERVAL_MINUTES` (60 by default). With `MEDIA_GC_DRY_RUN=true` it only
+/// logs what it would delete.
+pub struct MediaGc { db_pool: PgPool, store: web::Data<dyn MediaStore>, grace_hours: i32, interval: Duration, dry_run: bool }
+
+impl MediaGc {
+    pub fn new(db_pool: PgPool, store: web::Data<dyn MediaStore>) -> Self {
+        let grace_hours = env::var("MEDIA_GC_GRACE_HOURS").ok().and_then(|v| v.parse().ok()).unwrap_or(24);
+        let interval_minutes = env::var("MEDIA_GC_INTERVAL_MINUTES").ok().and_then(|v| v.parse().ok()).unwrap_or(60);
+        let dry_run = env::var("MEDIA_GC_DRY_RUN").is_ok_and(|v| matches!(v.as_str(), "1" | "true"));
+        Self { db_pool, store, grace_hours, interval: Duration::from_secs(interval_minutes * 60), dry_run }
+    }
+}
+
+impl Actor for MediaGc {
+    type Context = Context<Self>;
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if self.dry_run {
+            log::info!("Media GC is in dry-run mode; nothing will be deleted");
+        }
+        ctx.run_interval(self.interval, |act, ctx| {
+            let (db_pool, store, grace_hours, dry_run) = (act.db_pool.clone(), act.store.clone(), act.grace_hours, act.dry_run);
+            ctx.spawn(async move { run(&db_pool, store.get_ref(), grace_hours, dry_run).await }.into_actor(act));
+        });
+    }
+}
+
+async fn run(pool: &PgPool, store: &dyn MediaStore, grace_hours: i32, dry_run: bool) {
+    // Pending multipart uploads are left to `abort_abandoned_multiparts`, which also frees their parts
+    let orphans = match sqlx::query!(
+        r#"SELECT u.id, u.object_key, u.size_bytes, u.state as "state: MediaUploadState", u.thumbnails as "thumbnails: Json<Vec<Thumbnail>>"
+           FROM media_uploads u
+           WHERE (u.state IN ('pending', 'uploaded') AND u.updated_at < NOW() - make_interval(hours => $1) AND NOT (u.state = 'pending' AND u.multipart_id IS NOT NULL))
+              OR (u.state = 'attached' AND NOT EXISTS (SELECT 1 FROM messages m WHERE m.media->>'key' = u.object_key))"#,
+        grace_hours
+    )
+    .fetch_all(pool)
+    .await
+    {
+        Ok(rows) => rows,
+        Err(e) => { log::error!("Media GC could not find orphaned uploads: {}", e); return; }
+    };
+    let (mut unattached, mut detached, mut failed, mut freed_bytes) = (0, 0, 0, 0i64);
+    for upload in &orphans {
+        let reason = if upload.state == MediaUploadState::Attached { "its messages were deleted" } else { "it was never attached" };
+        if dry_run {
+            log::info!("Media GC (dry run) would delete {} ({} bytes): {}", upload.object_key, upload.size_bytes, reason);
+        } else {
+            let keys = std::iter::once(upload.object_key.as_str()).chain(upload.thumbnails.0.iter().map(|t| t.key.as_str()));
+            let mut deleted = true;
+            for key in keys {
+                if let Err(e) = store.delete(key).await {
+                    log::error!("Media GC failed to delete {}: {:?}", key, e);
+                    deleted = false;
+                    break;
+                }
+            }
+            let marked = deleted && match sqlx::query!("UPDATE media_uploads SET state = 'deleted', updated_at = NOW() WHERE id = $1", upload.id).execute(pool).await {
+                Ok(_) => true,
+                Err(e) => { log::error!("Media GC failed to mark upload {} deleted: {}", upload.id, e); false }
+            };
+            if !marked {
+                failed += 1;
+                continue;
+            }
+        }
+        if upload.state == MediaUploadState::Attached { detached += 1 } else { unattached += 1 }
+        freed_bytes += upload.size_bytes;
+    }
+    log::info!(
+        "Media GC{}: {} {} never-attached and {} detached uploads ({} bytes), {} failed",
+        if dry_run { " (dry run)" } else { "" },
+        if dry_run { "would delete" } else { "deleted" },
+        unattached,
+        detached,
+        freed_bytes,
+        failed
+    );
+}
@@ -0,0 +1,78 @@
+pub mod media_gc;
+
+use crate::handlers::{key_handler::PRE_KEY_CLAIM_WINDOW, multipart_upload_handler::abort_abandoned_multiparts, qr_auth_handler::qr_session_ttl};
+use crate::otp::policy::OtpPolicy;
+use crate::storage::MediaStore;
+use actix::{Actor, AsyncContext, Context, WrapFuture};
+use actix_web::web;
+use chrono::{Duration as ChronoDuration, Utc};
+use sqlx::PgPool;
+use std::{env, future::Future, time::Duration};
+
+const MINUTE: Duration = Duration::from_secs(60);
+const HOUR: Duration = Duration::from_secs(60 * 60);
+
+/// Runs `task` every `interval` and logs how many `what` it removed.
+pub struct PeriodicJob<F> { what: &'static str, interval: Duration, db_pool: PgPool, task: F }
+
+impl<F, Fut> PeriodicJob<F>
+where
+    F: Fn(PgPool) -> Fut + Unpin + 'static,
+    Fut: Future<Output = sqlx::Result<u64>> + 'static,
+{
+    pub fn start(what: &'static str, interval: Duration, db_pool: &PgPool, task: F) {
+        Actor::start(Self { what, interval, db_pool: db_pool.clone(), task });
+    }
+}
+
+impl<F, Fut> Actor for PeriodicJob<F>
+where
+    F: Fn(PgPool) -> Fut + Unpin + 'static,
+    Fut: Future<Output = sqlx::Result<u64>> + 'static,
+{
+    type Context = Context<Self>;
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(self.interval, |act, ctx| {
+            let (what, run) = (act.what, (act.task)(act.db_pool.clone()));
+            ctx.spawn(
+                async move {
+                    match run.await {
+                        Ok(0) => {}
+                        Ok(removed) => log::info!("Removed {} {}", removed, what),
+                        Err(e) => log::error!("Failed to remove {}: {}", what, e),
+                    }
+                }
+                .into_actor(act),
+            );
+        });
+    }
+}
+
+/// Housekeeping for rows that handlers already ignore once they are stale; it keeps tables small
+/// and frees what the store still holds for abandoned uploads.
+pub fn start_cleanups(db_pool: &PgPool, store: web::Data<dyn MediaStore>, otp_policy: OtpPolicy) {
+    // Multipart uploads without a new part for `MULTIPART_UPLOAD_TTL_HOURS` (24 by default) are aborted
+    let multipart_ttl_hours = env::var("MULTIPART_UPLOAD_TTL_HOURS").ok().and_then(|v| v.parse().ok()).unwrap_or(24);
+    PeriodicJob::start("abandoned multipart uploads", 15 * MINUTE, db_pool, move |pool| abort_abandoned_multiparts(pool, store.clone(), multipart_ttl_hours));
+    // Codes and send records are kept while they still count towards a resend limit
+    PeriodicJob::start("expired OTPs and old send records", 5 * MINUTE, db_pool, move |pool| {
+        let now = Utc::now();
+        let expired_before = now - ChronoDuration::seconds(otp_policy.lifetime_secs.max(otp_policy.phone_cooldown_secs));
+        let sends_before = now - ChronoDuration::seconds(otp_policy.ip_window_secs);
+        async move {
+            let otps = sqlx::query!("DELETE FROM temp_otps WHERE created_at < $1", expired_before).execute(&pool).await?;
+            let sends = sqlx::query!("DELETE FROM otp_sends WHERE sent_at < $1", sends_before).execute(&pool).await?;
+            Ok(otps.rows_affected() + sends.rows_affected())
+        }
+    });
+    // Rotated and revoked refresh tokens stay until they expire, so reuse is recognised as long as it matters
+    PeriodicJob::start("expired refresh tokens", HOUR, db_pool, |pool| async move {
+        Ok(sqlx::query!("DELETE FROM refresh_tokens WHERE expires_at < NOW()").execute(&pool).await?.rows_affected())
+    });
+    PeriodicJob::start("expired QR sessions", 5 * MINUTE, db_pool, |pool| async move {
+        Ok(sqlx::query!("DELETE FROM qr_sessions WHERE created_at < $1", Utc::now() - qr_session_ttl()).execute(&pool).await?.rows_affected())
+    });
+    PeriodicJob::start("prekey claims past the claim window", HOUR, db_pool, |pool| async move {
+        Ok(sqlx::query!("DELETE FROM pre_key_claims WHERE claimed_at < $1", Utc::now() - PRE_KEY_CLAIM_WINDOW).execute(&pool).await?.rows_affected())
+    });
+}
@@ -7,13 +7,19 @@ use std::env;
 
 mod actors;
 mod handlers;
+mod jobs;
 mod models;
+mod otp;
+mod storage;
 mod utils;
 
-use actors::server::ChatServer;
-use handlers::{auth_handler, conversation_handler, key_handler, media_handler, 
+use actors::{qr_login::QrLoginServer, server::ChatServer, thumbnailer::Thumbnailer};
+use handlers::{auth_handler, conversation_handler, device_handler, group_handler, jwks_handler, key_handler, local_media_handler, media_handler, multipart_upload_handler, otp_inbox_handler, 
 qr_auth_handler, user_handler, ws_handler};
 use utils::auth_middleware::JwtAuth; // <-- Import the middleware
+use jobs::media_gc::MediaGc;
+use otp::policy::OtpPolicy;
+use utils::media_policy::MediaPolicy;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -27,8 +33,10 @@ set").parse::<u16>().unwrap();
 
     log::info!("Starting server at http://{}:{}", host, port);
 
-    let aws_config = aws_config::load_from_env().await;
-    let s3_client = aws_sdk_s3::Client::new(&aws_config);
+    utils::jwt::keys();
+    let (media_store, local_store) = storage::from_env().await;
+    let (otp_sender, otp_inbox) = otp::from_env();
+    let otp_policy = OtpPolicy::from_env();
     let db_pool = 
 PgPoolOptions::new().max_connections(10).connect(&db_url).await.expect("DB pool 
 failed");
@@ -36,13 +44,22 @@ failed");
     sqlx::migrate!("./migrations").run(&db_pool).await.expect("Migrations 
 failed");
     log::info!("Database migrations completed.");
+    if let Err(e) = utils::phone::backfill_e164(&db_pool).await {
+        log::error!("Phone number backfill failed: {}", e);
+    }
 
     let chat_server = ChatServer::new(db_pool.clone()).start();
+    let qr_login = QrLoginServer::new(db_pool.clone(), chat_server.clone()).start();
+    let media_policy = web::Data::new(MediaPolicy::from_env());
+    let thumbnailer = Thumbnailer::new(db_pool.clone(), media_store.clone(), chat_server.clone()).start();
+    MediaGc::new(db_pool.clone(), media_store.clone()).start();
+    jobs::start_cleanups(&db_pool, media_store.clone(), otp_policy.clone());
+    let otp_policy = web::Data::new(otp_policy);
 
     HttpServer::new(move || {
         let cors = Cors::default()
             .allow_any_origin()
-            .allowed_methods(vec!["GET", "POST", "PUT", "DELETE"])
+            .allowed_methods(vec!["GET", "POST", "PUT", "PATCH", "DELETE"])
             .allowed_headers(vec![http::header::AUTHORIZATION, 
 http::header::ACCEPT, http::header::CONTENT_TYPE])
             .max_age(3600);
@@ -50,7 +67,12 @@ http::header::ACCEPT, http::header::CONTENT_TYPE])
         App::new()
             .app_data(web::Data::new(db_pool.clone()))
             .app_data(web::Data::new(chat_server.clone()))
-            .app_data(web::Data::new(s3_client.clone()))
+            .app_data(web::Data::new(qr_login.clone()))
+            .app_data(media_store.clone())
+            .app_data(otp_sender.clone())
+            .app_data(otp_policy.clone())
+            .app_data(media_policy.clone())
+            .app_data(web::Data::new(thumbnailer.clone()))
             .wrap(cors)
             .wrap(Logger::default())
             .service(
@@ -58,19 +80,27 @@ http::header::ACCEPT, http::header::CONTENT_TYPE])
                     // Public routes that DON'T need the middleware
                     .configure(auth_handler::config)
                     .configure(qr_auth_handler::config)
+                    .configure(|cfg| if let Some(inbox) = &otp_inbox { cfg.app_data(inbox.clone()); otp_inbox_handler::config(cfg); })
 
                     // Protected routes that DO need the middleware
                     .service(
                         web::scope("") // An empty scope to apply the middleware
                             .wrap(JwtAuth) // <-- APPLY THE MIDDLEWARE HERE
                             .configure(conversation_handler::config)
+                            .configure(group_handler::config)
                             // The following handlers are also now protected
                             .configure(key_handler::config)
+                            .configure(qr_auth_handler::phone_config)
+                            .configure(device_handler::config)
                             .configure(user_handler::config)
                             .configure(media_handler::config)
+                            .configure(multipart_upload_handler::config)
                     )
             )
             .route("/ws", web::get().to(ws_handler::ws_connect))
+            .configure(jwks_handler::config)
+            // Signed URLs of the local media store point here instead of at a bucket
+            .configure(|cfg| if let Some(local_store) = &local_store { cfg.app_data(local_store.clone()); local_media_handler::config(cfg); })
     })
     .bind((host, port))?
     .run()
@@ -1,9 +1,10 @@
+use crate::otp::OtpChannel;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
+use sqlx::{types::Json, FromRow};
 use uuid::Uuid;
 
-#[derive(Serialize, FromRow, Debug)]
+#[derive(Serialize, FromRow, Debug, Clone)]
 pub struct User { pub id: Uuid, pub phone_number: String, pub name: Option<String> 
 }
 
@@ -18,22 +19,178 @@ pub struct ConversationDetails {
     pub last_message_at: Option<DateTime<Utc>>,
 }
 
+#[derive(Serialize, FromRow, Debug, Clone)]
+pub struct Conversation {
+    pub id: Uuid,
+    pub is_group: bool,
+    pub group_name: Option<String>,
+    pub group_icon_url: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, FromRow, Debug, Clone)]
+pub struct Participant { pub user_id: Uuid, pub name: Option<String>, pub is_admin: bool }
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ConversationWithParticipants {
+    #[serde(flatten)]
+    pub conversation: Conversation,
+    pub participants: Vec<Participant>,
+}
+
+/// Body of `POST /conversations`. Direct chats name exactly one other user; groups need a name.
+#[derive(Deserialize)]
+pub struct CreateConversationRequest {
+    #[serde(default)]
+    pub is_group: bool,
+    pub participant_ids: Vec<Uuid>,
+    pub group_name: Option<String>,
+    pub group_icon_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "message_type", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum MessageType { #[default] Text, Image, Video, Audio, System, Document }
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "media_kind", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum MediaKind { Image, Video, Audio, Document }
+
+impl MediaKind {
+    /// The message type a message carrying this kind of upload must have.
+    pub fn message_type(self) -> MessageType {
+        match self { MediaKind::Image => MessageType::Image, MediaKind::Video => MessageType::Video, MediaKind::Audio => MessageType::Audio, MediaKind::Document => MessageType::Document }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "media_upload_state", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum MediaUploadState { Pending, Uploaded, Attached, Deleted }
+
+/// A row of `media_uploads`: one presigned upload and how far it has got.
+#[derive(Serialize, FromRow, Debug)]
+pub struct MediaUpload {
+    pub id: Uuid,
+    pub owner_id: Uuid,
+    pub object_key: String,
+    pub kind: MediaKind,
+    pub mime_type: String,
+    pub size_bytes: i64,
+    pub state: MediaUploadState,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Body of `POST /media/upload-url` and `POST /media/multipart`: what the client is about to upload.
+#[derive(Deserialize)]
+pub struct UploadUrlRequest { pub mime_type: String, pub size_bytes: i64 }
+
+/// Body of `PUT /media/multipart/{id}/parts/{part_number}`: the `ETag` the store returned for the part.
+#[derive(Deserialize)]
+pub struct RecordPartRequest { pub etag: String }
+
+/// An uploaded object attached to an image, video, audio or document message. Stored as JSONB in `messages.media`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaAttachment {
+    pub key: String,
+    pub mime_type: String,
+    pub size_bytes: i64,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub duration_ms: Option<i64>,
+    /// Filled in by the server once the upload's previews exist; whatever the client sends is ignored.
+    #[serde(default)]
+    pub thumbnails: Vec<Thumbnail>,
+    pub blurhash: Option<String>,
+}
+
+/// A downsized copy of an image, stored next to the original. It is a PNG if the image has
+/// transparency and a JPEG otherwise; the key ends in `.png` or `.jpg` accordingly.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Thumbnail { pub key: String, pub width: i32, pub height: i32 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "message_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum MessageStatus { Sent, Delivered, Read }
+
+#[derive(Deserialize)]
+pub struct AddParticipantsRequest { pub user_ids: Vec<Uuid> }
+#[derive(Deserialize)]
+pub struct SetAdminRequest { pub is_admin: bool }
+/// Body of `PATCH /conversations/{id}`. An empty `group_icon_url` removes the icon.
+#[derive(Deserialize)]
+pub struct UpdateGroupRequest { pub group_name: Option<String>, pub group_icon_url: Option<String> }
+
 #[derive(Serialize, FromRow, Debug)]
 #[sqlx(rename_all = "lowercase")]
 pub struct ChatMessage {
     pub id: Uuid,
     pub conversation_id: Uuid,
     pub sender_id: Uuid,
+    pub message_type: MessageType,
     pub content: String,
-    // We will handle message_type and status later
+    /// Aggregate over all recipients; per-recipient state lives in `message_receipts`.
+    pub status: MessageStatus,
+    pub media: Option<Json<MediaAttachment>>,
     pub created_at: DateTime<Utc>,
 }
 
+/// Query of `GET /conversations/{id}/messages`. Without a cursor the newest page is returned.
+#[derive(Deserialize)]
+pub struct MessageHistoryQuery { pub before: Option<Uuid>, pub after: Option<Uuid>, pub limit: Option<i64> }
+
+/// One page of history in ascending order. `next_cursor` continues in the direction that was
+/// requested (`before` by default) and is `None` once there is nothing further that way.
+#[derive(Serialize)]
+pub struct MessagePage { pub messages: Vec<ChatMessage>, pub next_cursor: Option<Uuid> }
+
+/// `device_id` identifies the login (and its refresh token family) the access token was issued to.
 #[derive(Debug, Serialize, Deserialize)]
-pub struct Claims { pub sub: String, pub exp: usize }
+pub struct Claims { pub sub: String, pub exp: usize, pub device_id: Uuid }
+#[derive(Deserialize)]
+pub struct SendOtpRequest { pub phone_number: String, #[serde(default)] pub channel: OtpChannel }
+/// `device_name` and `platform` describe the device signing in; the name defaults to its User-Agent.
+#[derive(Deserialize)]
+pub struct VerifyOtpRequest { pub phone_number: String, pub otp: String, pub device_name: Option<String>, pub platform: Option<DevicePlatform> }
+#[derive(Deserialize)]
+pub struct ContactLookupRequest { pub phone_numbers: Vec<String> }
+/// A number from the address book, as the client sent it, and the account registered under it.
+#[derive(Serialize)]
+pub struct ContactMatch { pub phone_number: String, pub user: User }
+/// `token` is the access token, valid for `expires_in` seconds; `refresh_token` renews it.
+#[derive(Serialize)]
+pub struct AuthResponse { pub token: String, pub refresh_token: String, pub expires_in: i64, pub user_id: String, pub device_id: Uuid }
+#[derive(Deserialize)]
+pub struct RefreshRequest { pub refresh_token: String }
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "device_platform", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum DevicePlatform { Android, Ios, Web, Desktop }
+
+/// A signed-in device. `current` marks the device making the request.
+#[derive(Serialize, FromRow, Debug)]
+pub struct Device { pub id: Uuid, pub name: String, pub platform: Option<DevicePlatform>, pub linked_at: DateTime<Utc>, pub last_active_at: DateTime<Utc>, pub current: bool }
+/// Keys and signatures are standard base64 of their raw bytes. The identity key is an Ed25519
+/// public key (32 bytes); prekeys are X25519 public keys (32 bytes, without the `0x05` type byte
+/// Signal prepends). `signature` is a plain Ed25519 signature (64 bytes) by the identity key over
+/// exactly the 32 decoded bytes of `public_key`; XEdDSA signatures from Curve25519 identity keys
+/// are not accepted.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedPreKey { pub key_id: i32, pub public_key: String, pub signature: String }
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OneTimePreKey { pub key_id: i32, pub public_key: String }
 #[derive(Deserialize)]
-pub struct SendOtpRequest { pub phone_number: String }
+pub struct UploadKeyBundleRequest { pub identity_key: String, pub signed_pre_key: SignedPreKey, #[serde(default)] pub one_time_pre_keys: Vec<OneTimePreKey> }
 #[derive(Deserialize)]
-pub struct VerifyOtpRequest { pub phone_number: String, pub otp: String }
+pub struct ReplenishPreKeysRequest { pub one_time_pre_keys: Vec<OneTimePreKey> }
+/// What another user needs to start a session. `one_time_pre_key` is `None` once they have run out,
+/// or the requester has taken too many of them recently; the session then starts from the signed prekey.
 #[derive(Serialize)]
-pub struct AuthResponse { pub token: String, pub user_id: String }
+pub struct KeyBundle { pub user_id: Uuid, pub identity_key: String, pub signed_pre_key: SignedPreKey, pub one_time_pre_key: Option<OneTimePreKey> }
+/// Query of `GET /auth/qr/new`: how the browser that will be linked describes itself.
+#[derive(Deserialize)]
+pub struct NewQrSessionQuery { pub device_name: Option<String>, pub platform: Option<DevicePlatform> }
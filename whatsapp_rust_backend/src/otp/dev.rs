@@ -0,0 +1,69 @@
+use super::{OtpChannel, OtpSender};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{collections::HashMap, env, path::PathBuf, sync::Mutex};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+
+/// Writes codes to the server log, which is all that happened before providers existed.
+pub struct LogOtpSender;
+
+#[async_trait]
+impl OtpSender for LogOtpSender {
+    async fn send_sms(&self, phone_number: &str, code: &str) -> Result<()> {
+        log::info!("OTP for {}: {}", phone_number, code);
+        Ok(())
+    }
+    async fn call(&self, phone_number: &str, code: &str) -> Result<()> { self.send_sms(phone_number, code).await }
+    fn supports_voice(&self) -> bool { true }
+}
+
+/// Appends `timestamp channel phone code` lines to `OTP_FILE` (`./otp_inbox.log` by default).
+pub struct FileOtpSender { path: PathBuf }
+
+impl FileOtpSender {
+    pub fn from_env() -> Self { Self { path: PathBuf::from(env::var("OTP_FILE").unwrap_or_else(|_| "./otp_inbox.log".to_owned())) } }
+
+    async fn append(&self, channel: OtpChannel, phone_number: &str, code: &str) -> Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path).await?;
+        file.write_all(format!("{} {} {} {}\n", Utc::now().to_rfc3339(), channel, phone_number, code).as_bytes()).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl OtpSender for FileOtpSender {
+    async fn send_sms(&self, phone_number: &str, code: &str) -> Result<()> { self.append(OtpChannel::Sms, phone_number, code).await }
+    async fn call(&self, phone_number: &str, code: &str) -> Result<()> { self.append(OtpChannel::Voice, phone_number, code).await }
+    fn supports_voice(&self) -> bool { true }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeliveredOtp { pub channel: OtpChannel, pub code: String, pub sent_at: DateTime<Utc> }
+
+/// Keeps codes in memory so tests can read them back from `GET /dev/otp-inbox/{phone_number}`.
+#[derive(Default)]
+pub struct InMemoryOtpSender { inbox: Mutex<HashMap<String, Vec<DeliveredOtp>>> }
+
+impl InMemoryOtpSender {
+    /// Everything sent to `phone_number`, oldest first.
+    pub fn messages_for(&self, phone_number: &str) -> Vec<DeliveredOtp> { self.inbox.lock().unwrap().get(phone_number).cloned().unwrap_or_default() }
+
+    fn push(&self, channel: OtpChannel, phone_number: &str, code: &str) {
+        self.inbox.lock().unwrap().entry(phone_number.to_owned()).or_default().push(DeliveredOtp { channel, code: code.to_owned(), sent_at: Utc::now() });
+    }
+}
+
+#[async_trait]
+impl OtpSender for InMemoryOtpSender {
+    async fn send_sms(&self, phone_number: &str, code: &str) -> Result<()> {
+        self.push(OtpChannel::Sms, phone_number, code);
+        Ok(())
+    }
+    async fn call(&self, phone_number: &str, code: &str) -> Result<()> {
+        self.push(OtpChannel::Voice, phone_number, code);
+        Ok(())
+    }
+    fn supports_voice(&self) -> bool { true }
+}
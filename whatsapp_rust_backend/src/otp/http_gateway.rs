@@ -0,0 +1,49 @@
+use super::OtpSender;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde_json::json;
+use std::{env, time::Duration};
+
+/// Sends codes through an HTTP SMS gateway: a JSON `POST {"to", "from", "body"}` to `OTP_HTTP_URL`
+/// with `OTP_HTTP_TOKEN` as a bearer token. If `OTP_HTTP_VOICE_URL` is set, voice calls are a
+/// `POST {"to", "from", "code", "body"}` there.
+pub struct HttpOtpSender { client: reqwest::Client, url: String, voice_url: Option<String>, token: String, from: String, template: String }
+
+impl HttpOtpSender {
+    pub fn from_env() -> Self {
+        Self {
+            client: reqwest::Client::builder().timeout(Duration::from_secs(10)).build().expect("Failed to build HTTP client"),
+            url: env::var("OTP_HTTP_URL").expect("OTP_HTTP_URL must be set"),
+            voice_url: env::var("OTP_HTTP_VOICE_URL").ok(),
+            token: env::var("OTP_HTTP_TOKEN").expect("OTP_HTTP_TOKEN must be set"),
+            from: env::var("OTP_HTTP_FROM").expect("OTP_HTTP_FROM must be set"),
+            template: env::var("OTP_MESSAGE_TEMPLATE").unwrap_or_else(|_| "Your verification code is {code}".to_owned()),
+        }
+    }
+
+    async fn post(&self, url: &str, body: serde_json::Value) -> Result<()> {
+        let response = self.client.post(url).bearer_auth(&self.token).json(&body).send().await.context("SMS gateway unreachable")?;
+        let status = response.status();
+        if !status.is_success() {
+            let detail = response.text().await.unwrap_or_default();
+            anyhow::bail!("SMS gateway answered {}: {}", status, detail);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl OtpSender for HttpOtpSender {
+    async fn send_sms(&self, phone_number: &str, code: &str) -> Result<()> {
+        self.post(&self.url, json!({"to": phone_number, "from": self.from, "body": self.template.replace("{code}", code)})).await
+    }
+
+    async fn call(&self, phone_number: &str, code: &str) -> Result<()> {
+        let Some(voice_url) = &self.voice_url else { anyhow::bail!("OTP_HTTP_VOICE_URL is not set") };
+        // Spaced digits are read out one by one by text-to-speech engines
+        let spoken: Vec<String> = code.chars().map(String::from).collect();
+        self.post(voice_url, json!({"to": phone_number, "from": self.from, "code": code, "body": self.template.replace("{code}", &spoken.join(" "))})).await
+    }
+
+    fn supports_voice(&self) -> bool { self.voice_url.is_some() }
+}
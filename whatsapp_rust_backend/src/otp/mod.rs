@@ -0,0 +1,55 @@
+pub mod dev;
+pub mod http_gateway;
+pub mod policy;
+
+use actix_web::web;
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{env, fmt, sync::Arc};
+
+use dev::{FileOtpSender, InMemoryOtpSender, LogOtpSender};
+use http_gateway::HttpOtpSender;
+
+/// How a code reaches the user. Voice is the fallback for users who cannot receive SMS.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OtpChannel { #[default] Sms, Voice }
+
+impl fmt::Display for OtpChannel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self { OtpChannel::Sms => write!(f, "sms"), OtpChannel::Voice => write!(f, "voice") }
+    }
+}
+
+/// Delivers one-time codes to phone numbers.
+#[async_trait]
+pub trait OtpSender: Send + Sync {
+    async fn send_sms(&self, phone_number: &str, code: &str) -> Result<()>;
+    /// Reads the code out in a phone call. Providers without voice keep the default.
+    async fn call(&self, _phone_number: &str, _code: &str) -> Result<()> { bail!("this provider cannot place voice calls") }
+    fn supports_voice(&self) -> bool { false }
+
+    async fn send(&self, channel: OtpChannel, phone_number: &str, code: &str) -> Result<()> {
+        match channel { OtpChannel::Sms => self.send_sms(phone_number, code).await, OtpChannel::Voice => self.call(phone_number, code).await }
+    }
+}
+
+/// Picks the provider from `OTP_SENDER`: `http` (an SMS gateway), `file`, `memory` or `log`, the
+/// default. The in-memory sender is also returned on its own because its inbox is served by this app.
+pub fn from_env() -> (web::Data<dyn OtpSender>, Option<web::Data<InMemoryOtpSender>>) {
+    let sender = env::var("OTP_SENDER").unwrap_or_else(|_| "log".to_owned());
+    if sender != "http" {
+        log::warn!("OTP_SENDER is '{}'; codes are not sent to real phones", sender);
+    }
+    match sender.as_str() {
+        "http" => (web::Data::from(Arc::new(HttpOtpSender::from_env()) as Arc<dyn OtpSender>), None),
+        "file" => (web::Data::from(Arc::new(FileOtpSender::from_env()) as Arc<dyn OtpSender>), None),
+        "memory" => {
+            let inbox = web::Data::new(InMemoryOtpSender::default());
+            (web::Data::from(inbox.clone().into_inner() as Arc<dyn OtpSender>), Some(inbox))
+        }
+        "log" => (web::Data::from(Arc::new(LogOtpSender) as Arc<dyn OtpSender>), None),
+        other => panic!("OTP_SENDER must be 'http', 'file', 'memory' or 'log', got '{}'", other),
+    }
+}
@@ -0,0 +1,111 @@
+use actix_web::{http::StatusCode, HttpRequest, HttpResponse, HttpResponseBuilder, ResponseError};
+use serde_json::json;
+use std::{env, fmt};
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T { env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default) }
+
+/// Limits on issuing and guessing codes, all in seconds:
+/// `OTP_EXPIRATION_SECONDS` (300), `OTP_MAX_ATTEMPTS` (5), `OTP_RESEND_COOLDOWN_SECONDS` (60),
+/// and at most `OTP_IP_MAX_SENDS` (10) sends per `OTP_IP_WINDOW_SECONDS` (3600) from one IP.
+/// With `TRUST_PROXY_HEADERS=true` that IP is the client address reported by the reverse proxy.
+#[derive(Debug, Clone)]
+pub struct OtpPolicy { pub lifetime_secs: i64, pub max_attempts: i32, pub phone_cooldown_secs: i64, pub ip_max_sends: i64, pub ip_window_secs: i64, pub trust_proxy_headers: bool }
+
+impl OtpPolicy {
+    pub fn from_env() -> Self {
+        Self {
+            lifetime_secs: env_or("OTP_EXPIRATION_SECONDS", 300),
+            max_attempts: env_or("OTP_MAX_ATTEMPTS", 5),
+            phone_cooldown_secs: env_or("OTP_RESEND_COOLDOWN_SECONDS", 60),
+            ip_max_sends: env_or("OTP_IP_MAX_SENDS", 10),
+            ip_window_secs: env_or("OTP_IP_WINDOW_SECONDS", 3600),
+            trust_proxy_headers: env_or("TRUST_PROXY_HEADERS", false),
+        }
+    }
+
+    /// The address sends are counted against. `Forwarded` and `X-Forwarded-For` can be set by
+    /// anyone, so they are only read when a trusted proxy in front of the server sets them.
+    pub fn client_ip(&self, req: &HttpRequest) -> String {
+        let info = req.connection_info();
+        let ip = if self.trust_proxy_headers { info.realip_remote_addr() } else { info.peer_addr() };
+        ip.unwrap_or_default().to_owned()
+    }
+}
+
+/// Why a code could not be sent or verified. Every variant has a stable `code` for clients.
+#[derive(Debug)]
+pub enum OtpError {
+    NotFound,
+    Expired,
+    Invalid { attempts_remaining: i32 },
+    Locked,
+    ResendTooSoon { retry_after_secs: i64 },
+    IpRateLimited { retry_after_secs: i64 },
+    VoiceUnavailable,
+    DeliveryFailed,
+    Database(sqlx::Error),
+}
+
+impl OtpError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            OtpError::NotFound => "otp_not_found",
+            OtpError::Expired => "otp_expired",
+            OtpError::Invalid { .. } => "otp_invalid",
+            OtpError::Locked => "otp_locked",
+            OtpError::ResendTooSoon { .. } => "otp_resend_too_soon",
+            OtpError::IpRateLimited { .. } => "otp_rate_limited",
+            OtpError::VoiceUnavailable => "voice_unavailable",
+            OtpError::DeliveryFailed => "otp_delivery_failed",
+            OtpError::Database(_) => "internal_error",
+        }
+    }
+}
+
+impl fmt::Display for OtpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OtpError::NotFound => write!(f, "No code was requested for this number"),
+            OtpError::Expired => write!(f, "The code has expired; request a new one"),
+            OtpError::Invalid { .. } => write!(f, "The code is incorrect"),
+            OtpError::Locked => write!(f, "Too many wrong codes; request a new one"),
+            OtpError::ResendTooSoon { .. } => write!(f, "A code was sent recently; wait before asking again"),
+            OtpError::IpRateLimited { .. } => write!(f, "Too many codes were requested; try again later"),
+            OtpError::VoiceUnavailable => write!(f, "Codes cannot be delivered by voice call"),
+            OtpError::DeliveryFailed => write!(f, "The code could not be sent; try again or ask for a voice call"),
+            OtpError::Database(_) => write!(f, "Something went wrong; try again"),
+        }
+    }
+}
+
+impl ResponseError for OtpError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            OtpError::NotFound => StatusCode::NOT_FOUND,
+            OtpError::Expired => StatusCode::GONE,
+            OtpError::Invalid { .. } => StatusCode::UNAUTHORIZED,
+            OtpError::Locked | OtpError::ResendTooSoon { .. } | OtpError::IpRateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            OtpError::VoiceUnavailable => StatusCode::BAD_REQUEST,
+            OtpError::DeliveryFailed => StatusCode::BAD_GATEWAY,
+            OtpError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+    fn error_response(&self) -> HttpResponse {
+        if let OtpError::Database(e) = self { log::error!("OTP query failed: {}", e); }
+        let mut body = json!({"code": self.code(), "message": self.to_string()});
+        let mut response = HttpResponseBuilder::new(self.status_code());
+        match self {
+            OtpError::Invalid { attempts_remaining } => body["attempts_remaining"] = json!(attempts_remaining),
+            OtpError::ResendTooSoon { retry_after_secs } | OtpError::IpRateLimited { retry_after_secs } => {
+                body["retry_after"] = json!(retry_after_secs);
+                response.insert_header(("Retry-After", retry_after_secs.to_string()));
+            }
+            _ => {}
+        }
+        response.json(body)
+    }
+}
+
+impl From<sqlx::Error> for OtpError {
+    fn from(e: sqlx::Error) -> Self { OtpError::Database(e) }
+}
@@ -0,0 +1,194 @@
+use super::{MediaStore, ObjectMeta, PresignedRequest, UploadedPart};
+use crate::utils::media_policy::mime_type_for_key;
+use actix_web::web::Bytes;
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use futures_util::{Stream, StreamExt};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    env,
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+use uuid::Uuid;
+use tokio::{fs, io::AsyncWriteExt};
+
+/// Keeps objects under a directory on disk and signs URLs that point back at this app's
+/// `/media/local/{key}` routes. Meant for development and CI, where there is no bucket.
+pub struct LocalStore { root: PathBuf, public_url: String, signing_key: Vec<u8> }
+
+impl LocalStore {
+    /// `MEDIA_LOCAL_ROOT` defaults to `./media` and `MEDIA_PUBLIC_URL` to `http://HOST:PORT`.
+    /// `MEDIA_SIGNING_KEY` has no default: it must not be a key that also signs anything else.
+    pub fn from_env() -> Self {
+        let root = PathBuf::from(env::var("MEDIA_LOCAL_ROOT").unwrap_or_else(|_| "./media".to_owned()));
+        let public_url = env::var("MEDIA_PUBLIC_URL").unwrap_or_else(|_| format!("http://{}:{}", env::var("HOST").expect("HOST must be set"), env::var("PORT").expect("PORT must be set")));
+        let signing_key = env::var("MEDIA_SIGNING_KEY").expect("MEDIA_SIGNING_KEY must be set when MEDIA_STORE=local");
+        Self { root, public_url: public_url.trim_end_matches('/').to_owned(), signing_key: signing_key.into_bytes() }
+    }
+
+    /// Parts of unfinished multipart uploads live under `root/.multipart/{multipart_id}/{part_number}`.
+    fn part_key(multipart_id: &str, part_number: i32) -> String { format!(".multipart/{}/{}", multipart_id, part_number) }
+
+    /// Only plain relative keys map onto the disk; anything that could escape `root` is refused.
+    fn path_for(&self, key: &str) -> Result<PathBuf> {
+        let relative = Path::new(key);
+        if key.is_empty() || !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+            bail!("invalid object key '{}'", key);
+        }
+        Ok(self.root.join(relative))
+    }
+
+    fn mac(&self, method: &str, key: &str, expires: i64, content_type: &str, content_length: Option<i64>) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.signing_key).expect("HMAC accepts keys of any length");
+        let content_length = content_length.map(|l| l.to_string()).unwrap_or_default();
+        mac.update(format!("{}\n{}\n{}\n{}\n{}", method, key, expires, content_type, content_length).as_bytes());
+        mac
+    }
+
+    fn signed_url(&self, method: &str, key: &str, expires_in: Duration, content_type: &str, content_length: Option<i64>) -> String {
+        let expires = Utc::now().timestamp() + expires_in.as_secs() as i64;
+        let signature = hex::encode(self.mac(method, key, expires, content_type, content_length).finalize().into_bytes());
+        format!("{}/media/local/{}?expires={}&signature={}", self.public_url, key, expires, signature)
+    }
+
+    /// Checks a request against the signature from `presign_upload` or `presign_download`.
+    /// Uploads must repeat the signed `Content-Type` and `Content-Length`.
+    pub fn verify(&self, method: &str, key: &str, expires: i64, content_type: &str, content_length: Option<i64>, signature: &str) -> bool {
+        let Ok(signature) = hex::decode(signature) else { return false };
+        expires >= Utc::now().timestamp() && self.mac(method, key, expires, content_type, content_length).verify_slice(&signature).is_ok()
+    }
+
+    /// Streams `body` into `key` and returns its ETag (the hex SHA-256 of the body), or `None`,
+    /// storing nothing, if it is not exactly `content_length` bytes.
+    pub async fn write<S, E>(&self, key: &str, mut body: S, content_length: i64) -> Result<Option<String>>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let path = self.path_for(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let partial = path.with_file_name(format!("{}.partial", path.file_name().unwrap_or_default().to_string_lossy()));
+        let mut file = fs::File::create(&partial).await?;
+        let mut written: i64 = 0;
+        let mut digest = Sha256::new();
+        while let Some(chunk) = body.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => { drop(file); let _ = fs::remove_file(&partial).await; return Err(e.into()); }
+            };
+            written += chunk.len() as i64;
+            if written > content_length {
+                break;
+            }
+            digest.update(&chunk);
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        drop(file);
+        if written != content_length {
+            fs::remove_file(&partial).await?;
+            return Ok(None);
+        }
+        fs::rename(&partial, &path).await?;
+        Ok(Some(hex::encode(digest.finalize())))
+    }
+}
+
+#[async_trait]
+impl MediaStore for LocalStore {
+    async fn presign_upload(&self, key: &str, content_type: &str, content_length: i64, expires_in: Duration) -> Result<PresignedRequest> {
+        self.path_for(key)?;
+        let headers = HashMap::from([("content-type".to_owned(), content_type.to_owned()), ("content-length".to_owned(), content_length.to_string())]);
+        Ok(PresignedRequest { method: "PUT", url: self.signed_url("PUT", key, expires_in, content_type, Some(content_length)), headers })
+    }
+
+    async fn presign_download(&self, key: &str, expires_in: Duration) -> Result<PresignedRequest> {
+        self.path_for(key)?;
+        Ok(PresignedRequest { method: "GET", url: self.signed_url("GET", key, expires_in, "", None), headers: Default::default() })
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<ObjectMeta>> {
+        match fs::metadata(self.path_for(key)?).await {
+            Ok(meta) => Ok(Some(ObjectMeta { size_bytes: meta.len() as i64, content_type: mime_type_for_key(key).map(str::to_owned) })),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match fs::read(self.path_for(key)?).await {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The content type is implied by the key's extension, so it is not stored.
+    async fn put(&self, key: &str, _content_type: &str, bytes: Vec<u8>) -> Result<()> {
+        let path = self.path_for(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::write(path, bytes).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        match fs::remove_file(self.path_for(key)?).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn start_multipart(&self, _key: &str, _content_type: &str) -> Result<String> {
+        let multipart_id = Uuid::new_v4().to_string();
+        fs::create_dir_all(self.path_for(&format!(".multipart/{}", multipart_id))?).await?;
+        Ok(multipart_id)
+    }
+
+    /// Parts are raw bytes, so they are signed as `application/octet-stream`.
+    async fn presign_part(&self, _key: &str, multipart_id: &str, part_number: i32, content_length: i64, expires_in: Duration) -> Result<PresignedRequest> {
+        let part_key = Self::part_key(multipart_id, part_number);
+        self.presign_upload(&part_key, "application/octet-stream", content_length, expires_in).await
+    }
+
+    async fn complete_multipart(&self, key: &str, multipart_id: &str, parts: &[UploadedPart]) -> Result<()> {
+        let path = self.path_for(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let partial = path.with_file_name(format!("{}.partial", path.file_name().unwrap_or_default().to_string_lossy()));
+        let mut file = fs::File::create(&partial).await?;
+        for part in parts {
+            let Some(bytes) = self.get(&Self::part_key(multipart_id, part.part_number)).await? else {
+                drop(file);
+                fs::remove_file(&partial).await?;
+                bail!("part {} of {} was never uploaded", part.part_number, multipart_id);
+            };
+            if hex::encode(Sha256::digest(&bytes)) != part.etag.trim_matches('"') {
+                drop(file);
+                fs::remove_file(&partial).await?;
+                bail!("part {} of {} does not match its ETag", part.part_number, multipart_id);
+            }
+            file.write_all(&bytes).await?;
+        }
+        file.flush().await?;
+        drop(file);
+        fs::rename(&partial, &path).await?;
+        self.abort_multipart(key, multipart_id).await
+    }
+
+    async fn abort_multipart(&self, _key: &str, multipart_id: &str) -> Result<()> {
+        match fs::remove_dir_all(self.path_for(&format!(".multipart/{}", multipart_id))?).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
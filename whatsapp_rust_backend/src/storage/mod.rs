@@ -0,0 +1,60 @@
+pub mod local;
+pub mod s3;
+
+use actix_web::web;
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::Serialize;
+use std::{collections::HashMap, env, time::Duration};
+
+use local::LocalStore;
+use s3::S3Store;
+
+/// A request the client sends straight to the store, bypassing our API.
+#[derive(Debug, Serialize)]
+pub struct PresignedRequest { pub method: &'static str, pub url: String, pub headers: HashMap<String, String> }
+
+/// A part of a multipart upload as reported by the client after its PUT succeeded.
+#[derive(Debug, Clone)]
+pub struct UploadedPart { pub part_number: i32, pub etag: String }
+
+#[derive(Debug)]
+pub struct ObjectMeta { pub size_bytes: i64, pub content_type: Option<String> }
+
+/// Where media objects live. Handlers only see this trait, so S3 and the local disk are interchangeable.
+#[async_trait]
+pub trait MediaStore: Send + Sync {
+    /// A PUT that only accepts exactly `content_type` and `content_length`.
+    async fn presign_upload(&self, key: &str, content_type: &str, content_length: i64, expires_in: Duration) -> Result<PresignedRequest>;
+    async fn presign_download(&self, key: &str, expires_in: Duration) -> Result<PresignedRequest>;
+    /// `None` if nothing is stored under `key`.
+    async fn head(&self, key: &str) -> Result<Option<ObjectMeta>>;
+    /// Reads a whole object into memory; `None` if nothing is stored under `key`.
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+    /// Writes an object produced by the server itself, such as a thumbnail.
+    async fn put(&self, key: &str, content_type: &str, bytes: Vec<u8>) -> Result<()>;
+    /// Deleting a missing object is not an error.
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    /// Starts a multipart upload to `key` and returns the store's id for it.
+    async fn start_multipart(&self, key: &str, content_type: &str) -> Result<String>;
+    /// A PUT for one part that only accepts exactly `content_length` bytes. Part numbers start at 1.
+    async fn presign_part(&self, key: &str, multipart_id: &str, part_number: i32, content_length: i64, expires_in: Duration) -> Result<PresignedRequest>;
+    /// Assembles `parts`, which must be in order, into the object at `key`.
+    async fn complete_multipart(&self, key: &str, multipart_id: &str, parts: &[UploadedPart]) -> Result<()>;
+    /// Discards every part uploaded so far.
+    async fn abort_multipart(&self, key: &str, multipart_id: &str) -> Result<()>;
+}
+
+/// Picks the backend from `MEDIA_STORE` (`s3`, the default, or `local`). The local store is also
+/// returned on its own because its signed URLs are served by routes in this app.
+pub async fn from_env() -> (web::Data<dyn MediaStore>, Option<web::Data<LocalStore>>) {
+    match env::var("MEDIA_STORE").as_deref().unwrap_or("s3") {
+        "s3" => (web::Data::from(std::sync::Arc::new(S3Store::from_env().await) as std::sync::Arc<dyn MediaStore>), None),
+        "local" => {
+            let local = web::Data::new(LocalStore::from_env());
+            (web::Data::from(local.clone().into_inner() as std::sync::Arc<dyn MediaStore>), Some(local))
+        }
+        other => panic!("MEDIA_STORE must be 's3' or 'local', got '{}'", other),
+    }
+}
@@ -0,0 +1,85 @@
+use super::{MediaStore, ObjectMeta, PresignedRequest, UploadedPart};
+use anyhow::Result;
+use async_trait::async_trait;
+use aws_sdk_s3::{
+    error::SdkError,
+    presigning::PresigningConfig,
+    primitives::ByteStream,
+    types::{CompletedMultipartUpload, CompletedPart},
+    Client,
+};
+use std::{env, time::Duration};
+
+pub struct S3Store { client: Client, bucket: String }
+
+impl S3Store {
+    pub async fn from_env() -> Self {
+        let aws_config = aws_config::load_from_env().await;
+        Self { client: Client::new(&aws_config), bucket: env::var("S3_BUCKET_NAME").expect("S3_BUCKET_NAME must be set") }
+    }
+}
+
+#[async_trait]
+impl MediaStore for S3Store {
+    async fn presign_upload(&self, key: &str, content_type: &str, content_length: i64, expires_in: Duration) -> Result<PresignedRequest> {
+        let presigned = self.client.put_object().bucket(&self.bucket).key(key).content_type(content_type).content_length(content_length).presigned(PresigningConfig::expires_in(expires_in)?).await?;
+        let headers = presigned.headers().iter().filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_owned()))).collect();
+        Ok(PresignedRequest { method: "PUT", url: presigned.uri().to_string(), headers })
+    }
+
+    async fn presign_download(&self, key: &str, expires_in: Duration) -> Result<PresignedRequest> {
+        let presigned = self.client.get_object().bucket(&self.bucket).key(key).presigned(PresigningConfig::expires_in(expires_in)?).await?;
+        Ok(PresignedRequest { method: "GET", url: presigned.uri().to_string(), headers: Default::default() })
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<ObjectMeta>> {
+        match self.client.head_object().bucket(&self.bucket).key(key).send().await {
+            Ok(out) => Ok(Some(ObjectMeta { size_bytes: out.content_length(), content_type: out.content_type().map(str::to_owned) })),
+            Err(SdkError::ServiceError(e)) if e.err().is_not_found() => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.client.get_object().bucket(&self.bucket).key(key).send().await {
+            Ok(out) => Ok(Some(out.body.collect().await?.into_bytes().to_vec())),
+            Err(SdkError::ServiceError(e)) if e.err().is_no_such_key() => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn put(&self, key: &str, content_type: &str, bytes: Vec<u8>) -> Result<()> {
+        self.client.put_object().bucket(&self.bucket).key(key).content_type(content_type).body(ByteStream::from(bytes)).send().await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.client.delete_object().bucket(&self.bucket).key(key).send().await?;
+        Ok(())
+    }
+
+    async fn start_multipart(&self, key: &str, content_type: &str) -> Result<String> {
+        let out = self.client.create_multipart_upload().bucket(&self.bucket).key(key).content_type(content_type).send().await?;
+        out.upload_id().map(str::to_owned).ok_or_else(|| anyhow::anyhow!("S3 returned no upload id for {}", key))
+    }
+
+    async fn presign_part(&self, key: &str, multipart_id: &str, part_number: i32, content_length: i64, expires_in: Duration) -> Result<PresignedRequest> {
+        let presigned = self.client.upload_part().bucket(&self.bucket).key(key).upload_id(multipart_id).part_number(part_number).content_length(content_length).presigned(PresigningConfig::expires_in(expires_in)?).await?;
+        let headers = presigned.headers().iter().filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_owned()))).collect();
+        Ok(PresignedRequest { method: "PUT", url: presigned.uri().to_string(), headers })
+    }
+
+    async fn complete_multipart(&self, key: &str, multipart_id: &str, parts: &[UploadedPart]) -> Result<()> {
+        let parts = parts.iter().map(|p| CompletedPart::builder().part_number(p.part_number).e_tag(&p.etag).build()).collect();
+        self.client.complete_multipart_upload().bucket(&self.bucket).key(key).upload_id(multipart_id).multipart_upload(CompletedMultipartUpload::builder().set_parts(Some(parts)).build()).send().await?;
+        Ok(())
+    }
+
+    async fn abort_multipart(&self, key: &str, multipart_id: &str) -> Result<()> {
+        match self.client.abort_multipart_upload().bucket(&self.bucket).key(key).upload_id(multipart_id).send().await {
+            Ok(_) => Ok(()),
+            Err(SdkError::ServiceError(e)) if e.err().is_no_such_upload() => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
@@ -1,12 +1,12 @@
-use crate::models::Claims;
-use crate::utils::jwt::decode_jwt;
+use crate::utils::{jwt::decode_jwt, refresh_tokens::touch_device};
 use actix_web::{
     dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    Error, HttpMessage,
+    web, Error, HttpMessage,
 };
 use futures_util::future::{LocalBoxFuture, Ready, ok};
-use std::future::Future;
-use std::pin::Pin;
+use sqlx::PgPool;
+use std::rc::Rc;
+use uuid::Uuid;
 
 pub struct JwtAuth;
 
@@ -23,12 +23,12 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ok(JwtAuthMiddleware { service })
+        ok(JwtAuthMiddleware { service: Rc::new(service) })
     }
 }
 
 pub struct JwtAuthMiddleware<S> {
-    service: S,
+    service: Rc<S>,
 }
 
 impl<S, B> Service<ServiceRequest> for JwtAuthMiddleware<S>
@@ -44,23 +44,25 @@ where
     forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        if let Some(auth_header) = req.headers().get("Authorization") {
-            if let Ok(auth_str) = auth_header.to_str() {
-                if auth_str.starts_with("Bearer ") {
-                    let token = &auth_str[7..];
-                    if let Ok(claims) = decode_jwt(token) {
-                        req.extensions_mut().insert(claims);
-                        let fut = self.service.call(req);
-                        return Box::pin(async move {
-                            fut.await
-                        });
-                    }
-                }
-            }
-        }
-        
+        let claims = req.headers().get("Authorization")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|s| s.strip_prefix("Bearer "))
+            .and_then(|token| decode_jwt(token).ok());
+        let service = Rc::clone(&self.service);
         Box::pin(async move {
-            Err(actix_web::error::ErrorUnauthorized("Invalid or missing token"))
+            let Some(claims) = claims else {
+                return Err(actix_web::error::ErrorUnauthorized("Invalid or missing token"));
+            };
+            // Access tokens of a removed device stop working immediately, not when they expire
+            let pool = req.app_data::<web::Data<PgPool>>().expect("PgPool is registered").clone();
+            let user_id = Uuid::parse_str(&claims.sub).map_err(|_| actix_web::error::ErrorUnauthorized("Invalid or missing token"))?;
+            match touch_device(&pool, user_id, claims.device_id).await {
+                Ok(true) => {}
+                Ok(false) => return Err(actix_web::error::ErrorUnauthorized("This device was signed out")),
+                Err(e) => { log::error!("Device check failed: {}", e); return Err(actix_web::error::ErrorInternalServerError("")); }
+            }
+            req.extensions_mut().insert(claims);
+            service.call(req).await
         })
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,107 @@
+use crate::actors::thumbnailer::thumbnail_source;
+use crate::handlers::media_handler::user_upload_prefix;
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use serde_json::json;
+use sqlx::{PgExecutor, PgPool};
+use std::fmt;
+use uuid::Uuid;
+
+/// Why a caller may not act on a conversation. Rendered as an HTTP error for REST handlers
+/// and as an `error` event for WebSocket clients, so both report the same `code`.
+#[derive(Debug)]
+pub enum AuthzError { NotParticipant, NotGroup, NotAdmin, NoMediaAccess, Database(sqlx::Error) }
+
+impl AuthzError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AuthzError::NotParticipant => "not_a_participant",
+            AuthzError::NotGroup => "not_a_group",
+            AuthzError::NotAdmin => "not_an_admin",
+            AuthzError::NoMediaAccess => "media_forbidden",
+            AuthzError::Database(_) => "internal_error",
+        }
+    }
+    pub fn ws_event(&self, conversation_id: Uuid) -> String {
+        json!({"event": "error", "data": {"code": self.code(), "message": self.to_string(), "conversation_id": conversation_id}}).to_string()
+    }
+}
+
+impl fmt::Display for AuthzError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthzError::NotParticipant => write!(f, "You are not a participant of this conversation"),
+            AuthzError::NotGroup => write!(f, "This action is only available in group conversations"),
+            AuthzError::NotAdmin => write!(f, "Only group admins can do this"),
+            AuthzError::NoMediaAccess => write!(f, "This media was not shared with you"),
+            AuthzError::Database(_) => write!(f, "Could not verify conversation membership"),
+        }
+    }
+}
+
+impl ResponseError for AuthzError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthzError::NotParticipant | AuthzError::NotAdmin | AuthzError::NoMediaAccess => StatusCode::FORBIDDEN,
+            AuthzError::NotGroup => StatusCode::BAD_REQUEST,
+            AuthzError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+    fn error_response(&self) -> HttpResponse {
+        if let AuthzError::Database(e) = self { log::error!("Membership check failed: {}", e); }
+        HttpResponse::build(self.status_code()).json(json!({"code": self.code(), "message": self.to_string()}))
+    }
+}
+
+impl From<sqlx::Error> for AuthzError {
+    fn from(e: sqlx::Error) -> Self { AuthzError::Database(e) }
+}
+
+/// Succeeds only if `user_id` has a row in `conversation_participants` for `conversation_id`.
+pub async fn require_participant(pool: &PgPool, conversation_id: Uuid, user_id: Uuid) -> Result<(), AuthzError> {
+    let is_participant = sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM conversation_participants WHERE conversation_id = $1 AND user_id = $2) as "exists!""#,
+        conversation_id,
+        user_id
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(AuthzError::Database)?;
+    if is_participant { Ok(()) } else { Err(AuthzError::NotParticipant) }
+}
+
+/// Checks that `conversation_id` is a group containing `user_id` and returns whether they are an admin.
+pub async fn group_role(conn: impl PgExecutor<'_>, conversation_id: Uuid, user_id: Uuid) -> Result<bool, AuthzError> {
+    let row = sqlx::query!(
+        "SELECT c.is_group, cp.is_admin FROM conversation_participants cp JOIN conversations c ON c.id = cp.conversation_id WHERE cp.conversation_id = $1 AND cp.user_id = $2",
+        conversation_id,
+        user_id
+    )
+    .fetch_optional(conn)
+    .await?;
+    match row {
+        None => Err(AuthzError::NotParticipant),
+        Some(row) if !row.is_group => Err(AuthzError::NotGroup),
+        Some(row) => Ok(row.is_admin),
+    }
+}
+
+/// Succeeds if `user_id` uploaded `key` or belongs to a conversation where a message carrying it was sent.
+/// A thumbnail is accessible exactly when its original is.
+pub async fn require_media_access(pool: &PgPool, key: &str, user_id: Uuid) -> Result<(), AuthzError> {
+    let key = thumbnail_source(key).unwrap_or(key);
+    if key.starts_with(&user_upload_prefix(user_id)) && !key.contains("..") {
+        return Ok(());
+    }
+    let shared = sqlx::query_scalar!(
+        r#"SELECT EXISTS(
+               SELECT 1 FROM messages m JOIN conversation_participants cp ON cp.conversation_id = m.conversation_id AND cp.user_id = $2
+               WHERE m.media IS NOT NULL AND m.media->>'key' = $1
+           ) as "exists!""#,
+        key,
+        user_id
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(AuthzError::Database)?;
+    if shared { Ok(()) } else { Err(AuthzError::NoMediaAccess) }
+}
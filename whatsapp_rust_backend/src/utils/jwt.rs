@@ -1,15 +1,126 @@
 use crate::models::Claims;
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use chrono::{Duration, Utc};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
-use std::env;
+use jsonwebtoken::jwk::{AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, OctetKeyPairParameters, PublicKeyUse, RSAKeyParameters};
+use jsonwebtoken::{decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rsa::{pkcs1::DecodeRsaPrivateKey, pkcs8::{DecodePrivateKey, DecodePublicKey}, traits::PublicKeyParts, RsaPrivateKey, RsaPublicKey};
+use std::{collections::HashMap, env, fs, path::Path, sync::OnceLock};
+use uuid::Uuid;
 
-pub fn create_jwt(user_id: &str) -> Result<String> {
-    let exp = Utc::now().checked_add_signed(Duration::hours(72)).expect("Failed to create expiration").timestamp();
-    let claims = Claims { sub: user_id.to_owned(), exp: exp as usize };
-    encode(&Header::default(), &claims, &EncodingKey::from_secret(env::var("JWT_SECRET").unwrap().as_ref())).map_err(Into::into)
+/// Lifetime of access tokens, from `ACCESS_TOKEN_TTL_MINUTES` (15 by default). Clients renew them
+/// with their refresh token, so a leaked access token is only useful briefly.
+pub fn access_token_ttl() -> Duration {
+    Duration::minutes(env::var("ACCESS_TOKEN_TTL_MINUTES").ok().and_then(|v| v.parse().ok()).unwrap_or(15))
 }
+
+/// The keys tokens are signed and checked with.
+///
+/// With `JWT_KEYS_DIR` set, every `{kid}.pem` in it is a key: a private key (RSA, for RS256, or
+/// Ed25519, for EdDSA) can sign, a public key only verifies. `JWT_SIGNING_KID` picks the signing key
+/// and may be omitted when there is only one private key. Tokens carry the `kid` of the key that
+/// signed them, so during a rotation the new key signs while the old one, kept as a public key,
+/// still verifies what it issued. Public keys are published at `/.well-known/jwks.json`.
+///
+/// Without `JWT_KEYS_DIR`, `JWT_SECRET` signs and verifies HS256 tokens without a `kid`. Once keys
+/// are set up those tokens are refused, since anyone holding the old secret could forge them; only
+/// `JWT_ACCEPT_LEGACY_HS256=true` keeps accepting them, for a short grace period after switching.
+pub struct KeySet { signing: (Option<String>, Algorithm, EncodingKey), verification: HashMap<String, (Algorithm, DecodingKey)>, legacy: Option<DecodingKey>, jwks: JwkSet }
+
+impl KeySet {
+    pub fn from_env() -> Result<Self> {
+        let secret = env::var("JWT_SECRET").ok().filter(|s| !s.is_empty());
+        let Ok(dir) = env::var("JWT_KEYS_DIR") else {
+            log::warn!("JWT_KEYS_DIR is not set; signing tokens with HS256 and JWT_SECRET");
+            let secret = secret.context("JWT_KEYS_DIR or JWT_SECRET must be set")?;
+            let signing = (None, Algorithm::HS256, EncodingKey::from_secret(secret.as_bytes()));
+            let legacy = Some(DecodingKey::from_secret(secret.as_bytes()));
+            return Ok(Self { signing, verification: HashMap::new(), legacy, jwks: JwkSet { keys: Vec::new() } });
+        };
+        let legacy = match (env::var("JWT_ACCEPT_LEGACY_HS256").is_ok_and(|v| matches!(v.as_str(), "1" | "true")), &secret) {
+            (false, _) => None,
+            (true, Some(secret)) => {
+                log::warn!("JWT_ACCEPT_LEGACY_HS256 is set; tokens without a kid are still accepted if JWT_SECRET signed them. Unset it once they have expired");
+                Some(DecodingKey::from_secret(secret.as_bytes()))
+            }
+            (true, None) => bail!("JWT_ACCEPT_LEGACY_HS256 needs the JWT_SECRET that signed those tokens"),
+        };
+        let (mut signing_keys, mut verification, mut jwks) = (HashMap::new(), HashMap::new(), Vec::new());
+        for entry in fs::read_dir(&dir).with_context(|| format!("cannot read JWT_KEYS_DIR {}", dir))? {
+            let path = entry?.path();
+            let Some(kid) = path.file_stem().and_then(|s| s.to_str()).filter(|_| path.extension().is_some_and(|e| e == "pem")) else { continue };
+            let (encoding_key, jwk) = load_key(kid, &path).with_context(|| format!("cannot load JWT key {}", path.display()))?;
+            let algorithm = jwk.common.algorithm.expect("keys are loaded with their algorithm");
+            if let Some(encoding_key) = encoding_key {
+                signing_keys.insert(kid.to_owned(), (algorithm, encoding_key));
+            }
+            verification.insert(kid.to_owned(), (algorithm, DecodingKey::from_jwk(&jwk)?));
+            jwks.push(jwk);
+        }
+        let signing_kid = match env::var("JWT_SIGNING_KID") {
+            Ok(kid) => kid,
+            Err(_) if signing_keys.len() == 1 => signing_keys.keys().next().unwrap().clone(),
+            Err(_) => bail!("{} private keys in {}; set JWT_SIGNING_KID to choose one", signing_keys.len(), dir),
+        };
+        let (algorithm, encoding_key) = signing_keys.remove(&signing_kid).ok_or_else(|| anyhow!("no private key {}.pem in {}", signing_kid, dir))?;
+        log::info!("Signing tokens with {:?} key '{}'; {} keys verify", algorithm, signing_kid, verification.len());
+        jwks.sort_by(|a, b| a.common.key_id.cmp(&b.common.key_id));
+        Ok(Self { signing: (Some(signing_kid), algorithm, encoding_key), verification, legacy, jwks: JwkSet { keys: jwks } })
+    }
+
+    pub fn jwks(&self) -> &JwkSet { &self.jwks }
+}
+
+/// Reads a PEM key and describes its public half as a JWK. The encoding key is only returned for private keys.
+fn load_key(kid: &str, path: &Path) -> Result<(Option<EncodingKey>, Jwk)> {
+    let pem = fs::read_to_string(path)?;
+    let jwk = |algorithm, parameters| Jwk {
+        common: CommonParameters { public_key_use: Some(PublicKeyUse::Signature), algorithm: Some(algorithm), key_id: Some(kid.to_owned()), ..Default::default() },
+        algorithm: parameters,
+    };
+    let rsa = |key: RsaPublicKey| {
+        let (n, e) = (URL_SAFE_NO_PAD.encode(key.n().to_bytes_be()), URL_SAFE_NO_PAD.encode(key.e().to_bytes_be()));
+        jwk(Algorithm::RS256, AlgorithmParameters::RSA(RSAKeyParameters { n, e, ..Default::default() }))
+    };
+    let ed25519 = |key: ed25519_dalek::VerifyingKey| {
+        let x = URL_SAFE_NO_PAD.encode(key.as_bytes());
+        jwk(Algorithm::EdDSA, AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters { curve: EllipticCurve::Ed25519, x, ..Default::default() }))
+    };
+    if pem.contains("PUBLIC KEY") {
+        if let Ok(key) = RsaPublicKey::from_public_key_pem(&pem) { return Ok((None, rsa(key))); }
+        if let Ok(key) = ed25519_dalek::VerifyingKey::from_public_key_pem(&pem) { return Ok((None, ed25519(key))); }
+        bail!("not an RSA or Ed25519 public key");
+    }
+    if let Ok(key) = RsaPrivateKey::from_pkcs8_pem(&pem).or_else(|_| RsaPrivateKey::from_pkcs1_pem(&pem)) {
+        return Ok((Some(EncodingKey::from_rsa_pem(pem.as_bytes())?), rsa(key.to_public_key())));
+    }
+    if let Ok(key) = ed25519_dalek::SigningKey::from_pkcs8_pem(&pem) {
+        return Ok((Some(EncodingKey::from_ed_pem(pem.as_bytes())?), ed25519(key.verifying_key())));
+    }
+    bail!("not an RSA or Ed25519 private key in PKCS#8 (or PKCS#1) form")
+}
+
+static KEYS: OnceLock<KeySet> = OnceLock::new();
+
+/// Loads the keyset once. Called at startup so a broken key directory stops the server there.
+pub fn keys() -> &'static KeySet {
+    KEYS.get_or_init(|| KeySet::from_env().unwrap_or_else(|e| panic!("Failed to load JWT keys: {:?}", e)))
+}
+
+/// An access token for `user_id` on `device_id`, the login the token's refresh token family belongs to.
+pub fn create_jwt(user_id: &str, device_id: Uuid) -> Result<String> {
+    let exp = Utc::now().checked_add_signed(access_token_ttl()).expect("Failed to create expiration").timestamp();
+    let claims = Claims { sub: user_id.to_owned(), exp: exp as usize, device_id };
+    let (kid, algorithm, key) = &keys().signing;
+    let header = Header { kid: kid.clone(), ..Header::new(*algorithm) };
+    encode(&header, &claims, key).map_err(Into::into)
+}
+/// Checks `token` with the key its `kid` names, and only with that key's algorithm.
 pub fn decode_jwt(token: &str) -> Result<Claims> {
-    decode::<Claims>(token, &DecodingKey::from_secret(env::var("JWT_SECRET").unwrap().as_ref()), &Validation::default()).map(|d| 
-d.claims).map_err(Into::into)
+    let keys = keys();
+    let (algorithm, key) = match decode_header(token)?.kid {
+        Some(kid) => keys.verification.get(&kid).map(|(algorithm, key)| (*algorithm, key)).ok_or_else(|| anyhow!("unknown kid '{}'", kid))?,
+        None => (Algorithm::HS256, keys.legacy.as_ref().ok_or_else(|| anyhow!("token has no kid"))?),
+    };
+    decode::<Claims>(token, key, &Validation::new(algorithm)).map(|d| d.claims).map_err(Into::into)
 }
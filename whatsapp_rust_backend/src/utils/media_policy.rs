@@ -0,0 +1,149 @@
+use crate::models::MediaKind;
+use std::env;
+
+/// Mime types the server knows how to store, with the extension used for their object keys.
+const KNOWN_TYPES: &[(&str, MediaKind, &str)] = &[
+    ("image/jpeg", MediaKind::Image, "jpg"),
+    ("image/png", MediaKind::Image, "png"),
+    ("image/webp", MediaKind::Image, "webp"),
+    ("image/gif", MediaKind::Image, "gif"),
+    ("video/mp4", MediaKind::Video, "mp4"),
+    ("video/3gpp", MediaKind::Video, "3gp"),
+    ("video/quicktime", MediaKind::Video, "mov"),
+    ("audio/ogg", MediaKind::Audio, "ogg"),
+    ("audio/mpeg", MediaKind::Audio, "mp3"),
+    ("audio/mp4", MediaKind::Audio, "m4a"),
+    ("audio/aac", MediaKind::Audio, "aac"),
+    ("audio/amr", MediaKind::Audio, "amr"),
+    ("application/pdf", MediaKind::Document, "pdf"),
+    ("text/plain", MediaKind::Document, "txt"),
+    ("application/zip", MediaKind::Document, "zip"),
+    ("application/msword", MediaKind::Document, "doc"),
+    ("application/vnd.openxmlformats-officedocument.wordprocessingml.document", MediaKind::Document, "docx"),
+    ("application/vnd.ms-excel", MediaKind::Document, "xls"),
+    ("application/vnd.openxmlformats-officedocument.spreadsheetml.sheet", MediaKind::Document, "xlsx"),
+    ("application/vnd.ms-powerpoint", MediaKind::Document, "ppt"),
+    ("application/vnd.openxmlformats-officedocument.presentationml.presentation", MediaKind::Document, "pptx"),
+];
+
+/// The mime type implied by an object key's extension, for stores that do not keep one themselves.
+pub fn mime_type_for_key(key: &str) -> Option<&'static str> {
+    let extension = key.rsplit_once('.')?.1;
+    KNOWN_TYPES.iter().find(|(_, _, e)| *e == extension).map(|(m, _, _)| *m)
+}
+
+const MIB: i64 = 1024 * 1024;
+
+#[derive(Debug, Clone)]
+struct KindRule { kind: MediaKind, max_bytes: i64, mime_types: Vec<String> }
+
+/// What a client may upload. Each kind has a size cap (`MEDIA_<KIND>_MAX_BYTES`) and an
+/// allowlist of mime types (`MEDIA_<KIND>_TYPES`, comma separated, limited to `KNOWN_TYPES`).
+#[derive(Debug, Clone)]
+pub struct MediaPolicy { rules: Vec<KindRule> }
+
+/// An upload the policy accepted, with the extension its object key should use.
+#[derive(Debug, Clone, Copy)]
+pub struct AcceptedUpload { pub kind: MediaKind, pub extension: &'static str }
+
+#[derive(Debug)]
+pub enum PolicyViolation { UnsupportedType, Empty, TooLarge { max_bytes: i64 } }
+
+impl PolicyViolation {
+    pub fn code(&self) -> &'static str {
+        match self { PolicyViolation::UnsupportedType => "unsupported_media_type", PolicyViolation::Empty => "empty_upload", PolicyViolation::TooLarge { .. } => "upload_too_large" }
+    }
+    pub fn message(&self) -> String {
+        match self {
+            PolicyViolation::UnsupportedType => "This file type cannot be uploaded".to_owned(),
+            PolicyViolation::Empty => "Uploads must not be empty".to_owned(),
+            PolicyViolation::TooLarge { max_bytes } => format!("Uploads of this type are limited to {} bytes", max_bytes),
+        }
+    }
+}
+
+impl MediaPolicy {
+    pub fn from_env() -> Self {
+        let defaults = [(MediaKind::Image, "IMAGE", 16 * MIB), (MediaKind::Video, "VIDEO", 64 * MIB), (MediaKind::Audio, "AUDIO", 16 * MIB), (MediaKind::Document, "DOCUMENT", 100 * MIB)];
+        let rules = defaults
+            .into_iter()
+            .map(|(kind, name, default_max)| {
+                let max_bytes = env::var(format!("MEDIA_{}_MAX_BYTES", name)).ok().and_then(|v| v.parse().ok()).unwrap_or(default_max);
+                let mime_types = match env::var(format!("MEDIA_{}_TYPES", name)) {
+                    Ok(list) => list.split(',').map(|t| t.trim().to_ascii_lowercase()).filter(|t| KNOWN_TYPES.iter().any(|(m, k, _)| m == t && *k == kind)).collect(),
+                    Err(_) => KNOWN_TYPES.iter().filter(|(_, k, _)| *k == kind).map(|(m, _, _)| m.to_string()).collect(),
+                };
+                KindRule { kind, max_bytes, mime_types }
+            })
+            .collect();
+        Self { rules }
+    }
+
+    pub fn check(&self, mime_type: &str, size_bytes: i64) -> Result<AcceptedUpload, PolicyViolation> {
+        let mime_type = mime_type.trim().to_ascii_lowercase();
+        let (_, kind, extension) = KNOWN_TYPES.iter().find(|(m, _, _)| *m == mime_type).ok_or(PolicyViolation::UnsupportedType)?;
+        let rule = self.rules.iter().find(|r| r.kind == *kind && r.mime_types.contains(&mime_type)).ok_or(PolicyViolation::UnsupportedType)?;
+        if size_bytes <= 0 {
+            return Err(PolicyViolation::Empty);
+        }
+        if size_bytes > rule.max_bytes {
+            return Err(PolicyViolation::TooLarge { max_bytes: rule.max_bytes });
+        }
+        Ok(AcceptedUpload { kind: *kind, extension })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(kind: MediaKind, max_bytes: i64, mime_types: &[&str]) -> KindRule {
+        KindRule { kind, max_bytes, mime_types: mime_types.iter().map(|m| m.to_string()).collect() }
+    }
+
+    fn policy() -> MediaPolicy {
+        MediaPolicy { rules: vec![
+            rule(MediaKind::Image, 16 * MIB, &["image/jpeg", "image/png"]),
+            rule(MediaKind::Video, 64 * MIB, &["video/mp4"]),
+            rule(MediaKind::Audio, 16 * MIB, &["audio/ogg"]),
+            rule(MediaKind::Document, 100 * MIB, &["application/pdf"]),
+        ] }
+    }
+
+    #[test]
+    fn each_kind_has_its_own_limit() {
+        let policy = policy();
+        for (mime_type, kind, max_bytes) in [("image/jpeg", MediaKind::Image, 16 * MIB), ("video/mp4", MediaKind::Video, 64 * MIB), ("audio/ogg", MediaKind::Audio, 16 * MIB), ("application/pdf", MediaKind::Document, 100 * MIB)] {
+            let accepted = policy.check(mime_type, max_bytes).unwrap();
+            assert!(accepted.kind == kind, "{} was not accepted as {:?}", mime_type, kind);
+            assert!(matches!(policy.check(mime_type, max_bytes + 1), Err(PolicyViolation::TooLarge { max_bytes: m }) if m == max_bytes));
+        }
+        // A video-sized image is still refused
+        assert!(matches!(policy.check("image/png", 64 * MIB), Err(PolicyViolation::TooLarge { .. })));
+    }
+
+    #[test]
+    fn size_boundaries() {
+        let policy = policy();
+        assert!(policy.check("image/jpeg", 16 * MIB).is_ok());
+        assert!(matches!(policy.check("image/jpeg", 16 * MIB + 1), Err(PolicyViolation::TooLarge { .. })));
+        assert!(policy.check("image/jpeg", 1).is_ok());
+        assert!(matches!(policy.check("image/jpeg", 0), Err(PolicyViolation::Empty)));
+        assert!(matches!(policy.check("image/jpeg", -1), Err(PolicyViolation::Empty)));
+    }
+
+    #[test]
+    fn disallowed_mime_types() {
+        let policy = policy();
+        assert!(matches!(policy.check("application/x-msdownload", 1), Err(PolicyViolation::UnsupportedType)));
+        assert!(matches!(policy.check("", 1), Err(PolicyViolation::UnsupportedType)));
+        // Known to the server but left out of the image allowlist
+        assert!(matches!(policy.check("image/gif", 1), Err(PolicyViolation::UnsupportedType)));
+    }
+
+    #[test]
+    fn mime_types_are_matched_case_insensitively_with_their_extension() {
+        let accepted = policy().check(" Image/PNG ", 1).unwrap();
+        assert_eq!(accepted.extension, "png");
+    }
+}
@@ -1,2 +1,6 @@
 pub mod auth_middleware;
+pub mod authz;
 pub mod jwt;
+pub mod media_policy;
+pub mod phone;
+pub mod refresh_tokens;
\ No newline at end of file
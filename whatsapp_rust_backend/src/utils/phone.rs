@@ -0,0 +1,213 @@
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use chrono::{DateTime, Utc};
+use phonenumber::{country, Mode};
+use serde_json::json;
+use sqlx::PgPool;
+use std::{collections::HashMap, env, fmt};
+use uuid::Uuid;
+
+/// A phone number that does not parse, or is not a valid number for its country.
+#[derive(Debug)]
+pub struct InvalidPhoneNumber(pub String);
+
+impl fmt::Display for InvalidPhoneNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}' is not a valid phone number; include the country code, e.g. +14155552671", self.0)
+    }
+}
+
+impl ResponseError for InvalidPhoneNumber {
+    fn status_code(&self) -> StatusCode { StatusCode::BAD_REQUEST }
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::BadRequest().json(json!({"code": "invalid_phone_number", "message": self.to_string()}))
+    }
+}
+
+/// The region assumed for numbers written without a country code, from `DEFAULT_PHONE_REGION`
+/// (an ISO 3166 code such as `US`). Without it, numbers must start with `+` or an international prefix.
+fn default_region() -> Option<country::Id> {
+    let region = env::var("DEFAULT_PHONE_REGION").ok()?;
+    region.trim().to_uppercase().parse().map_err(|_| log::warn!("DEFAULT_PHONE_REGION '{}' is not a known region", region)).ok()
+}
+
+/// Parses `input` however the user typed it ("+1 (415) 555-2671", "+44 20 7946 0958", "4155552671")
+/// and returns its E.164 form, which is how numbers are stored and looked up.
+pub fn normalize_phone(input: &str) -> Result<String, InvalidPhoneNumber> {
+    normalize_phone_in(input, default_region())
+}
+
+fn normalize_phone_in(input: &str, region: Option<country::Id>) -> Result<String, InvalidPhoneNumber> {
+    let invalid = || InvalidPhoneNumber(input.to_owned());
+    let number = phonenumber::parse(region, input).map_err(|_| invalid())?;
+    if !number.is_valid() {
+        return Err(invalid());
+    }
+    Ok(number.format().mode(Mode::E164).to_string())
+}
+
+/// Why `backfill_e164` left a number alone.
+#[derive(Debug, Clone, Copy, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "phone_review_reason", rename_all = "lowercase")]
+enum ReviewReason { Invalid, Collision }
+
+/// A number stored before numbers were normalized.
+struct StoredNumber { user_id: Uuid, phone_number: String, last_seen: DateTime<Utc> }
+
+#[derive(Debug, PartialEq)]
+enum Backfill { Normalize(String), Review { reason: ReviewReason, normalized: Option<String>, kept_user_id: Option<Uuid> } }
+
+/// Decides what happens to each stored number, with the rules logins use. Of the accounts whose
+/// numbers normalize alike, one that already has the E.164 form (`taken`) keeps it, otherwise the
+/// most recently seen does; the others are left for review, as are numbers that are not valid.
+fn plan_backfill<'a>(stored: &'a [StoredNumber], taken: &HashMap<String, Uuid>, region: Option<country::Id>) -> Vec<(&'a StoredNumber, Backfill)> {
+    let mut plan = Vec::new();
+    let mut groups: HashMap<String, Vec<&StoredNumber>> = HashMap::new();
+    for number in stored {
+        match normalize_phone_in(&number.phone_number, region) {
+            Ok(normalized) => groups.entry(normalized).or_default().push(number),
+            Err(_) => plan.push((number, Backfill::Review { reason: ReviewReason::Invalid, normalized: None, kept_user_id: None })),
+        }
+    }
+    for (normalized, mut numbers) in groups {
+        numbers.sort_by(|a, b| b.last_seen.cmp(&a.last_seen).then(a.user_id.cmp(&b.user_id)));
+        let kept_user_id = match taken.get(&normalized) {
+            Some(user_id) => *user_id,
+            None => {
+                let kept = numbers.remove(0);
+                plan.push((kept, Backfill::Normalize(normalized.clone())));
+                kept.user_id
+            }
+        };
+        for number in numbers {
+            plan.push((number, Backfill::Review { reason: ReviewReason::Collision, normalized: Some(normalized.clone()), kept_user_id: Some(kept_user_id) }));
+        }
+    }
+    plan
+}
+
+/// Converts numbers stored before normalization to E.164, so their owners find their accounts on
+/// the next login. Runs at startup; once every number is converted or listed in
+/// `phone_number_review` it finds nothing to do.
+pub async fn backfill_e164(pool: &PgPool) -> sqlx::Result<()> {
+    let stored = sqlx::query_as!(
+        StoredNumber,
+        r#"SELECT id as user_id, phone_number, last_seen FROM users WHERE phone_number !~ '^\+[0-9]+$' AND id NOT IN (SELECT user_id FROM phone_number_review)"#
+    )
+    .fetch_all(pool)
+    .await?;
+    if stored.is_empty() {
+        return Ok(());
+    }
+    let region = default_region();
+    let normalized: Vec<String> = stored.iter().filter_map(|n| normalize_phone_in(&n.phone_number, region).ok()).collect();
+    let taken: HashMap<String, Uuid> = sqlx::query!("SELECT id, phone_number FROM users WHERE phone_number = ANY($1)", &normalized)
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|u| (u.phone_number, u.id))
+        .collect();
+    let plan = plan_backfill(&stored, &taken, region);
+    let mut tx = pool.begin().await?;
+    let mut converted = 0;
+    for (number, action) in &plan {
+        match action {
+            Backfill::Normalize(normalized) => {
+                sqlx::query!("UPDATE users SET phone_number = $2 WHERE id = $1", number.user_id, normalized).execute(&mut *tx).await?;
+                converted += 1;
+            }
+            Backfill::Review { reason, normalized, kept_user_id } => {
+                log::warn!("Phone number '{}' of user {} was left unchanged ({:?}); see phone_number_review", number.phone_number, number.user_id, reason);
+                sqlx::query!(
+                    "INSERT INTO phone_number_review (user_id, original_phone_number, reason, normalized_phone_number, kept_user_id) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (user_id) DO NOTHING",
+                    number.user_id,
+                    number.phone_number,
+                    *reason as ReviewReason,
+                    normalized.as_deref(),
+                    *kept_user_id
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+    }
+    tx.commit().await?;
+    log::info!("Converted {} phone numbers to E.164; {} left for review", converted, plan.len() - converted);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    const US: Option<country::Id> = Some(country::Id::US);
+
+    /// Input as typed, the region assumed for it, and its E.164 form (`None` when it is refused).
+    const CASES: &[(&str, Option<country::Id>, Option<&str>)] = &[
+        ("4155552671", US, Some("+14155552671")),
+        ("(415) 555-2671", US, Some("+14155552671")),
+        ("415.555.2671", US, Some("+14155552671")),
+        ("+1 415-555-2671", US, Some("+14155552671")),
+        ("+14155552671", US, Some("+14155552671")),
+        ("+44 20 7946 0958", US, Some("+442079460958")),
+        ("011 44 20 7946 0958", US, Some("+442079460958")),
+        ("0044 20 7946 0958", Some(country::Id::GB), Some("+442079460958")),
+        ("020 7946 0958", Some(country::Id::GB), Some("+442079460958")),
+        ("+14155552671", None, Some("+14155552671")),
+        // Without a region only international forms can be read
+        ("4155552671", None, None),
+        ("", US, None),
+        ("   ", US, None),
+        ("12345", US, None),
+        ("+1 555 0000", US, None),
+        ("phone", US, None),
+    ];
+
+    #[test]
+    fn normalizes_numbers_as_typed() {
+        for (input, region, expected) in CASES {
+            assert_eq!(normalize_phone_in(input, *region).ok().as_deref(), *expected, "normalizing {:?} in {:?}", input, region);
+        }
+    }
+
+    fn stored(phone_number: &str, seen_days_ago: i64) -> StoredNumber {
+        StoredNumber { user_id: Uuid::new_v4(), phone_number: phone_number.to_owned(), last_seen: Utc::now() - Duration::days(seen_days_ago) }
+    }
+
+    fn action_for<'a>(plan: &'a [(&StoredNumber, Backfill)], number: &StoredNumber) -> &'a Backfill {
+        &plan.iter().find(|(n, _)| n.user_id == number.user_id).expect("every number is planned").1
+    }
+
+    #[test]
+    fn backfill_matches_normalize_phone() {
+        for (input, region, expected) in CASES {
+            let numbers = [stored(input, 0)];
+            let plan = plan_backfill(&numbers, &HashMap::new(), *region);
+            let action = action_for(&plan, &numbers[0]);
+            match expected {
+                Some(e164) => assert_eq!(action, &Backfill::Normalize(e164.to_string()), "backfilling {:?}", input),
+                None => assert_eq!(action, &Backfill::Review { reason: ReviewReason::Invalid, normalized: None, kept_user_id: None }, "backfilling {:?}", input),
+            }
+        }
+    }
+
+    #[test]
+    fn backfill_keeps_the_most_recently_seen_account() {
+        let numbers = [stored("4155552671", 3), stored("(415) 555-2671", 1), stored("415 555 2671", 2)];
+        let plan = plan_backfill(&numbers, &HashMap::new(), US);
+        assert_eq!(action_for(&plan, &numbers[1]), &Backfill::Normalize("+14155552671".to_owned()));
+        for loser in [&numbers[0], &numbers[2]] {
+            let expected = Backfill::Review { reason: ReviewReason::Collision, normalized: Some("+14155552671".to_owned()), kept_user_id: Some(numbers[1].user_id) };
+            assert_eq!(action_for(&plan, loser), &expected);
+        }
+    }
+
+    #[test]
+    fn backfill_never_takes_a_number_already_in_use() {
+        let existing = Uuid::new_v4();
+        let numbers = [stored("4155552671", 0)];
+        let plan = plan_backfill(&numbers, &HashMap::from([("+14155552671".to_owned(), existing)]), US);
+        let expected = Backfill::Review { reason: ReviewReason::Collision, normalized: Some("+14155552671".to_owned()), kept_user_id: Some(existing) };
+        assert_eq!(action_for(&plan, &numbers[0]), &expected);
+    }
+}
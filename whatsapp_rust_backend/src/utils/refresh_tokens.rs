@@ -0,0 +1,187 @@
+use crate::actors::server::{ChatServer, DeviceLinked};
+use crate::models::{AuthResponse, Device, DevicePlatform};
+use crate::utils::jwt::{access_token_ttl, create_jwt};
+use actix::Addr;
+use actix_web::{http::StatusCode, HttpRequest, HttpResponse, ResponseError};
+use chrono::{Duration, Utc};
+use rand::{thread_rng, RngCore};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{PgExecutor, PgPool};
+use std::{env, fmt};
+use uuid::Uuid;
+
+/// How stale a device's `last_active_at` may get before a request refreshes it.
+const DEVICE_ACTIVITY_RESOLUTION: Duration = Duration::minutes(1);
+
+/// Lifetime of a refresh token, from `REFRESH_TOKEN_TTL_DAYS` (30 by default). Each rotation
+/// issues a token with a fresh lifetime, so only devices idle for that long are signed out.
+pub fn refresh_token_ttl() -> Duration {
+    Duration::days(env::var("REFRESH_TOKEN_TTL_DAYS").ok().and_then(|v| v.parse().ok()).unwrap_or(30))
+}
+
+/// Why a refresh token was not accepted. Every variant has a stable `code` for clients.
+#[derive(Debug)]
+pub enum RefreshError { Invalid, Expired, Revoked, Reused { device_id: Uuid }, Signing(anyhow::Error), Database(sqlx::Error) }
+
+impl RefreshError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            RefreshError::Invalid => "invalid_refresh_token",
+            RefreshError::Expired => "refresh_token_expired",
+            RefreshError::Revoked => "refresh_token_revoked",
+            RefreshError::Reused { .. } => "refresh_token_reused",
+            RefreshError::Signing(_) | RefreshError::Database(_) => "internal_error",
+        }
+    }
+}
+
+impl fmt::Display for RefreshError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RefreshError::Invalid => write!(f, "The refresh token is not recognised"),
+            RefreshError::Expired => write!(f, "The session has expired; sign in again"),
+            RefreshError::Revoked => write!(f, "The session was signed out; sign in again"),
+            RefreshError::Reused { .. } => write!(f, "The refresh token was already used, so the session was signed out; sign in again"),
+            RefreshError::Signing(_) | RefreshError::Database(_) => write!(f, "Something went wrong; try again"),
+        }
+    }
+}
+
+impl ResponseError for RefreshError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            RefreshError::Signing(_) | RefreshError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            RefreshError::Database(e) => log::error!("Refresh token query failed: {}", e),
+            RefreshError::Signing(e) => log::error!("Failed to sign access token: {:?}", e),
+            _ => {}
+        }
+        HttpResponse::build(self.status_code()).json(json!({"code": self.code(), "message": self.to_string()}))
+    }
+}
+
+impl From<sqlx::Error> for RefreshError {
+    fn from(e: sqlx::Error) -> Self { RefreshError::Database(e) }
+}
+
+/// Tokens are 256 random bits, so a plain SHA-256 is enough to keep the stored form useless.
+fn hash_token(token: &str) -> String { hex::encode(Sha256::digest(token.as_bytes())) }
+
+/// Stores a new refresh token in `device_id`'s family and returns it; only its hash is kept.
+async fn issue<'c>(executor: impl PgExecutor<'c>, user_id: Uuid, device_id: Uuid) -> sqlx::Result<String> {
+    let mut secret = [0u8; 32];
+    thread_rng().fill_bytes(&mut secret);
+    let token = hex::encode(secret);
+    let expires_at = Utc::now() + refresh_token_ttl();
+    sqlx::query!("INSERT INTO refresh_tokens (user_id, device_id, token_hash, expires_at) VALUES ($1, $2, $3, $4)", user_id, device_id, hash_token(&token), expires_at)
+        .execute(executor)
+        .await?;
+    Ok(token)
+}
+
+fn auth_response(user_id: Uuid, device_id: Uuid, refresh_token: String) -> anyhow::Result<AuthResponse> {
+    let token = create_jwt(&user_id.to_string(), device_id)?;
+    Ok(AuthResponse { token, refresh_token, expires_in: access_token_ttl().num_seconds(), user_id: user_id.to_string(), device_id })
+}
+
+const MAX_DEVICE_NAME_CHARS: usize = 64;
+
+/// How a device signing in describes itself.
+pub struct NewDevice { pub name: String, pub platform: Option<DevicePlatform> }
+
+impl NewDevice {
+    /// Devices that do not name themselves are named after their User-Agent.
+    pub fn describe(name: Option<&str>, platform: Option<DevicePlatform>, req: &HttpRequest) -> Self {
+        let user_agent = req.headers().get("User-Agent").and_then(|v| v.to_str().ok());
+        let name = name.or(user_agent).map(str::trim).filter(|n| !n.is_empty()).unwrap_or("Unknown device");
+        Self { name: name.chars().take(MAX_DEVICE_NAME_CHARS).collect(), platform }
+    }
+}
+
+/// Signs `user_id` in on a new device: the device is registered, gets a new token family and an
+/// access token bound to it, and the user's other devices are told about it.
+pub async fn sign_in(pool: &PgPool, chat_server: &Addr<ChatServer>, user_id: Uuid, device: NewDevice) -> anyhow::Result<AuthResponse> {
+    let mut tx = pool.begin().await?;
+    let device = sqlx::query_as!(
+        Device,
+        r#"INSERT INTO devices (id, user_id, name, platform) VALUES ($1, $2, $3, $4)
+           RETURNING id, name, platform as "platform: DevicePlatform", linked_at, last_active_at, FALSE as "current!""#,
+        Uuid::new_v4(),
+        user_id,
+        device.name,
+        device.platform as Option<DevicePlatform>
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    let refresh_token = issue(&mut *tx, user_id, device.id).await?;
+    tx.commit().await?;
+    let auth = auth_response(user_id, device.id, refresh_token)?;
+    chat_server.do_send(DeviceLinked { user_id, device });
+    Ok(auth)
+}
+
+/// Exchanges a refresh token for a new access token and its successor. The old token is claimed
+/// atomically, so it rotates at most once; presenting it again means it leaked (or the client
+/// lost the successor), and the whole family is revoked.
+pub async fn rotate(pool: &PgPool, token: &str) -> Result<AuthResponse, RefreshError> {
+    let token_hash = hash_token(token);
+    let mut tx = pool.begin().await?;
+    let claimed = sqlx::query!(
+        "UPDATE refresh_tokens SET rotated_at = NOW() WHERE token_hash = $1 AND rotated_at IS NULL AND revoked_at IS NULL AND expires_at > NOW() RETURNING user_id, device_id",
+        token_hash
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    if let Some(claimed) = claimed {
+        let refresh_token = issue(&mut *tx, claimed.user_id, claimed.device_id).await?;
+        tx.commit().await?;
+        return auth_response(claimed.user_id, claimed.device_id, refresh_token).map_err(RefreshError::Signing);
+    }
+    tx.rollback().await?;
+    let Some(existing) = sqlx::query!("SELECT device_id, rotated_at, revoked_at FROM refresh_tokens WHERE token_hash = $1", token_hash).fetch_optional(pool).await? else {
+        return Err(RefreshError::Invalid);
+    };
+    if existing.revoked_at.is_some() { return Err(RefreshError::Revoked); }
+    if existing.rotated_at.is_some() {
+        log::warn!("Rotated refresh token of device {} was reused; revoking the device", existing.device_id);
+        revoke_device(pool, existing.device_id).await?;
+        return Err(RefreshError::Reused { device_id: existing.device_id });
+    }
+    Err(RefreshError::Expired)
+}
+
+/// Revokes every token of the family `token` belongs to and returns its device, or `None` if
+/// the token is unknown. Revoking an already revoked family is not an error.
+pub async fn revoke(pool: &PgPool, token: &str) -> sqlx::Result<Option<Uuid>> {
+    let device_id = sqlx::query_scalar!("SELECT device_id FROM refresh_tokens WHERE token_hash = $1", hash_token(token)).fetch_optional(pool).await?;
+    if let Some(device_id) = device_id {
+        revoke_device(pool, device_id).await?;
+    }
+    Ok(device_id)
+}
+
+/// Revokes the device's refresh tokens and removes it, which also stops its access tokens from
+/// being accepted. Its WebSocket connections are closed by the caller with `DisconnectDevice`.
+pub async fn revoke_device(pool: &PgPool, device_id: Uuid) -> sqlx::Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!("UPDATE refresh_tokens SET revoked_at = NOW() WHERE device_id = $1 AND revoked_at IS NULL", device_id).execute(&mut *tx).await?;
+    sqlx::query!("DELETE FROM devices WHERE id = $1", device_id).execute(&mut *tx).await?;
+    tx.commit().await
+}
+
+/// Whether `device_id` is still signed in as `user_id`. This runs on every authenticated request,
+/// so it only reads; activity is written at most once a minute per device.
+pub async fn touch_device(pool: &PgPool, user_id: Uuid, device_id: Uuid) -> sqlx::Result<bool> {
+    let last_active_at = sqlx::query_scalar!("SELECT last_active_at FROM devices WHERE id = $1 AND user_id = $2", device_id, user_id).fetch_optional(pool).await?;
+    let Some(last_active_at) = last_active_at else { return Ok(false) };
+    let stale_before = Utc::now() - DEVICE_ACTIVITY_RESOLUTION;
+    if last_active_at < stale_before {
+        sqlx::query!("UPDATE devices SET last_active_at = NOW() WHERE id = $1 AND last_active_at < $2", device_id, stale_before).execute(pool).await?;
+    }
+    Ok(true)
+}